@@ -1,14 +1,26 @@
+// Spotify has an embed for each of these; the server tells us which one we got in X-Item-Type.
+var EMBEDDABLE = ["track", "episode", "album", "playlist", "artist", "show"];
+
 function loadSong() {
   var xhttp = new XMLHttpRequest();
-  var pre = '<iframe style="border-radius:12px" src="https://open.spotify.com/embed/track/';
+  var pre = '<iframe style="border-radius:12px" src="https://open.spotify.com/embed/';
   var post = ' width="80%" height="152" frameBorder="0" allowfullscreen="" allow="autoplay; clipboard-write; encrypted-media; fullscreen; picture-in-picture" loading="lazy"></iframe>';
   xhttp.onreadystatechange = function() {
     if (this.readyState == 4) {
+      var type = this.getResponseHeader("X-Item-Type") || "track";
       switch (this.status) {
         case 200:
-          document.getElementById("song").innerHTML = pre + this.responseText + post;
+          if (EMBEDDABLE.indexOf(type) == -1) {
+            document.getElementById("song").innerHTML = "Playing something Spotify can't embed.";
+            break;
+          }
+          document.getElementById("song").innerHTML = pre + type + '/' + this.responseText + '"' + post;
           break;
         case 204:
+          if (type == "ad") {
+            document.getElementById("song").innerHTML = "Listening to an ad. Check back in a bit!"
+            break;
+          }
           document.getElementById("song").innerHTML = "Nothing right now! Check back later."
           break;
         case 503:
@@ -0,0 +1,463 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+
+use parking_lot::RwLock;
+
+use rcgen::{Certificate, CertificateParams, DistinguishedName};
+
+use reqwest::header;
+
+use ring::digest::{digest, SHA256};
+use ring::rand::SystemRandom;
+use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use axum_server::tls_rustls::RustlsConfig;
+
+use tokio::{task, time};
+
+use crate::conf::AcmeConfig;
+
+/// Let's Encrypt hands out 90-day certificates and suggests renewing with a month to spare.
+const RENEW_BEFORE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+/// How often the renewal loop wakes up to check.
+const CHECK_EVERY: Duration = Duration::from_secs(12 * 60 * 60);
+/// How long to wait between polls while the ACME server validates or issues.
+const POLL_EVERY: Duration = Duration::from_secs(2);
+const POLL_ATTEMPTS: u32 = 30;
+
+/// HTTP-01 challenge responses we're currently waiting on the ACME server to fetch, keyed by token.
+/// The plain HTTP server reads these while everything else gets redirected.
+#[derive(Debug, Default)]
+pub struct Challenges {
+    tokens: RwLock<HashMap<String, String>>,
+}
+
+impl Challenges {
+    pub fn get(&self, token: &str) -> Option<String> {
+        self.tokens.read().get(token).cloned()
+    }
+
+    fn insert(&self, token: String, key_authorization: String) {
+        self.tokens.write().insert(token, key_authorization);
+    }
+
+    fn remove(&self, token: &str) {
+        self.tokens.write().remove(token);
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+#[derive(Deserialize)]
+struct Order {
+    status: String,
+    #[serde(default)]
+    authorizations: Vec<String>,
+    finalize: String,
+    certificate: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Authorization {
+    status: String,
+    challenges: Vec<Challenge>,
+}
+
+#[derive(Deserialize)]
+struct Challenge {
+    #[serde(rename = "type")]
+    kind: String,
+    url: String,
+    #[serde(default)]
+    token: String,
+}
+
+/// An ACME account, plus everything needed to sign requests as it.
+/// https://datatracker.ietf.org/doc/html/rfc8555
+struct Account {
+    client: reqwest::Client,
+    directory: Directory,
+    key: EcdsaKeyPair,
+    rng: SystemRandom,
+    kid: Option<String>,
+    nonce: Option<String>,
+}
+
+impl Account {
+    /// Loads (or makes) the account key from the cache and registers it.
+    /// Registering an existing key just hands back the existing account, so this is safe to repeat.
+    async fn open(config: &AcmeConfig) -> Result<Account, String> {
+        let mut client = reqwest::Client::builder();
+        if let Some(ca) = &config.ca_cert {
+            let pem =
+                fs::read(ca).map_err(|e| format!("Failed to read {}: {}", ca.display(), e))?;
+            client = client.add_root_certificate(
+                reqwest::Certificate::from_pem(&pem).map_err(|e| e.to_string())?,
+            );
+        }
+        let client = client.build().map_err(|e| e.to_string())?;
+
+        let directory = client
+            .get(&config.directory)
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(|e| format!("Failed to fetch ACME directory: {}", e))?
+            .json::<Directory>()
+            .await
+            .map_err(|e| format!("Failed to parse ACME directory: {}", e))?;
+
+        let rng = SystemRandom::new();
+        let key_path = config.cache.join("account.key");
+        let pkcs8 = match fs::read(&key_path) {
+            Ok(pkcs8) => pkcs8,
+            Err(_) => {
+                let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng)
+                    .map_err(|_| "Failed to generate an ACME account key!")?;
+                write_private(&key_path, pkcs8.as_ref())?;
+                pkcs8.as_ref().to_vec()
+            }
+        };
+        let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &pkcs8, &rng)
+            .map_err(|e| format!("Bad ACME account key in {}: {}", key_path.display(), e))?;
+
+        let mut account = Account {
+            client,
+            directory,
+            key,
+            rng,
+            kid: None,
+            nonce: None,
+        };
+        let new_account = account.directory.new_account.clone();
+        let response = account
+            .post(
+                &new_account,
+                Some(&json!({
+                    "termsOfServiceAgreed": true,
+                    "contact": config.contact.iter().map(|c| format!("mailto:{}", c)).collect::<Vec<_>>(),
+                })),
+            )
+            .await?;
+        account.kid = Some(location(&response)?);
+        Ok(account)
+    }
+
+    /// The account's public key as a JWK.
+    /// serde_json keeps object keys sorted and compact, which is exactly the form RFC 7638 wants for thumbprints.
+    fn jwk(&self) -> Value {
+        let point = self.key.public_key().as_ref();
+        json!({
+            "crv": "P-256",
+            "kty": "EC",
+            "x": URL_SAFE_NO_PAD.encode(&point[1..33]),
+            "y": URL_SAFE_NO_PAD.encode(&point[33..65]),
+        })
+    }
+
+    fn key_authorization(&self, token: &str) -> String {
+        let thumbprint = digest(&SHA256, self.jwk().to_string().as_bytes());
+        format!("{}.{}", token, URL_SAFE_NO_PAD.encode(thumbprint))
+    }
+
+    async fn fresh_nonce(&mut self) -> Result<String, String> {
+        if let Some(nonce) = self.nonce.take() {
+            return Ok(nonce);
+        }
+        let response = self
+            .client
+            .head(&self.directory.new_nonce)
+            .send()
+            .await
+            .map_err(|e| format!("Failed to get an ACME nonce: {}", e))?;
+        replay_nonce(&response).ok_or(String::from("ACME server didn't give us a nonce!"))
+    }
+
+    /// Sends a JWS-signed request. `None` for the payload makes it a POST-as-GET.
+    /// Nonces go stale, so a badNonce gets one retry with the fresh one the server sent back.
+    async fn post(
+        &mut self,
+        url: &str,
+        payload: Option<&Value>,
+    ) -> Result<reqwest::Response, String> {
+        let payload = payload.map_or(String::new(), |p| URL_SAFE_NO_PAD.encode(p.to_string()));
+        for attempt in 0..2 {
+            let mut protected = json!({
+                "alg": "ES256",
+                "nonce": self.fresh_nonce().await?,
+                "url": url,
+            });
+            match &self.kid {
+                Some(kid) => protected["kid"] = json!(kid),
+                None => protected["jwk"] = self.jwk(),
+            }
+            let protected = URL_SAFE_NO_PAD.encode(protected.to_string());
+            let signature = self
+                .key
+                .sign(&self.rng, format!("{}.{}", protected, payload).as_bytes())
+                .map_err(|_| "Failed to sign ACME request!")?;
+            let body = json!({
+                "protected": protected,
+                "payload": payload,
+                "signature": URL_SAFE_NO_PAD.encode(signature.as_ref()),
+            });
+
+            let response = self
+                .client
+                .post(url)
+                .header(header::CONTENT_TYPE, "application/jose+json")
+                .body(body.to_string())
+                .send()
+                .await
+                .map_err(|e| format!("Failed to reach ACME server at {}: {}", url, e))?;
+            self.nonce = replay_nonce(&response);
+            if response.status().is_success() {
+                return Ok(response);
+            }
+
+            let status = response.status();
+            let problem = response.json::<Value>().await.unwrap_or_default();
+            if attempt == 0 && problem["type"] == "urn:ietf:params:acme:error:badNonce" {
+                continue;
+            }
+            return Err(format!(
+                "ACME server said {} for {}: {}",
+                status, url, problem
+            ));
+        }
+        unreachable!()
+    }
+
+    async fn post_as_get<T: for<'de> Deserialize<'de>>(&mut self, url: &str) -> Result<T, String> {
+        self.post(url, None)
+            .await?
+            .json::<T>()
+            .await
+            .map_err(|e| format!("Failed to parse ACME response from {}: {}", url, e))
+    }
+
+    /// Keeps asking about an order or authorization until it stops being pending.
+    async fn poll<T: for<'de> Deserialize<'de>>(
+        &mut self,
+        url: &str,
+        done: impl Fn(&T) -> bool,
+    ) -> Result<T, String> {
+        for _ in 0..POLL_ATTEMPTS {
+            let current = self.post_as_get::<T>(url).await?;
+            if done(&current) {
+                return Ok(current);
+            }
+            time::sleep(POLL_EVERY).await;
+        }
+        Err(format!("Gave up waiting on {}", url))
+    }
+}
+
+/// Gets a certificate if we don't have one, or the one we have is close to expiring.
+/// Returns whether a new certificate was written.
+pub async fn ensure_certificate(
+    config: &AcmeConfig,
+    challenges: &Challenges,
+) -> Result<bool, String> {
+    if !needs_renewal(&config.cert_path(), &config.key_path()) {
+        return Ok(false);
+    }
+    fs::create_dir_all(&config.cache)
+        .map_err(|e| format!("Failed to create {}: {}", config.cache.display(), e))?;
+    issue(config, challenges).await?;
+    Ok(true)
+}
+
+/// Checks on the certificate every so often, renewing it and swapping it into the TLS listener as needed.
+pub fn spawn_renewal(config: AcmeConfig, challenges: Arc<Challenges>, tls_config: RustlsConfig) {
+    task::spawn(async move {
+        loop {
+            time::sleep(CHECK_EVERY).await;
+            match ensure_certificate(&config, &challenges).await {
+                Ok(true) => {
+                    if let Err(e) = tls_config
+                        .reload_from_pem_file(config.cert_path(), config.key_path())
+                        .await
+                    {
+                        tracing::error!("Renewed the certificate but failed to load it: {}", e);
+                    }
+                }
+                Ok(false) => (),
+                Err(e) => tracing::warn!("Failed to renew the certificate: {}", e),
+            }
+        }
+    });
+}
+
+/// Whether it's time for a new certificate: there isn't one, it's close to expiring,
+/// or the key next to it isn't the one it was issued for.
+fn needs_renewal(cert_path: &Path, key_path: &Path) -> bool {
+    let pem = match fs::read(cert_path) {
+        Ok(pem) => pem,
+        Err(_) => return true,
+    };
+    let not_after = match x509_parser::pem::parse_x509_pem(&pem) {
+        Ok((_, pem)) => match pem.parse_x509() {
+            Ok(cert) if key_matches(key_path, cert.public_key().raw) => {
+                cert.validity().not_after.timestamp()
+            }
+            _ => return true,
+        },
+        Err(_) => return true,
+    };
+    let renew_at = UNIX_EPOCH + Duration::from_secs(not_after.max(0) as u64) - RENEW_BEFORE;
+    SystemTime::now() >= renew_at
+}
+
+async fn issue(config: &AcmeConfig, challenges: &Challenges) -> Result<(), String> {
+    let mut account = Account::open(config).await?;
+
+    let new_order = account.directory.new_order.clone();
+    let response = account
+        .post(
+            &new_order,
+            Some(&json!({
+                "identifiers": config
+                    .domains
+                    .iter()
+                    .map(|domain| json!({ "type": "dns", "value": domain }))
+                    .collect::<Vec<_>>(),
+            })),
+        )
+        .await?;
+    let order_url = location(&response)?;
+    let order = response
+        .json::<Order>()
+        .await
+        .map_err(|e| format!("Failed to parse ACME order: {}", e))?;
+
+    for authz_url in order.authorizations.iter() {
+        let authz = account.post_as_get::<Authorization>(authz_url).await?;
+        if authz.status == "valid" {
+            continue;
+        }
+        let challenge = authz
+            .challenges
+            .iter()
+            .find(|c| c.kind == "http-01")
+            .ok_or("ACME server didn't offer an HTTP-01 challenge!")?;
+
+        challenges.insert(
+            challenge.token.clone(),
+            account.key_authorization(&challenge.token),
+        );
+        let result = async {
+            account.post(&challenge.url, Some(&json!({}))).await?;
+            account
+                .poll::<Authorization>(authz_url, |a| a.status != "pending")
+                .await
+        }
+        .await;
+        challenges.remove(&challenge.token);
+        match result?.status.as_str() {
+            "valid" => (),
+            other => return Err(format!("Authorization {} ended up {}", authz_url, other)),
+        }
+    }
+
+    let mut params = CertificateParams::new(config.domains.clone());
+    params.distinguished_name = DistinguishedName::new();
+    let cert = Certificate::from_params(params).map_err(|e| e.to_string())?;
+    let csr = cert.serialize_request_der().map_err(|e| e.to_string())?;
+    account
+        .post(
+            &order.finalize,
+            Some(&json!({ "csr": URL_SAFE_NO_PAD.encode(csr) })),
+        )
+        .await?;
+
+    let order = account
+        .poll::<Order>(&order_url, |o| {
+            o.status != "pending" && o.status != "processing"
+        })
+        .await?;
+    let cert_url = match (order.status.as_str(), order.certificate) {
+        ("valid", Some(url)) => url,
+        (other, _) => return Err(format!("Order {} ended up {}", order_url, other)),
+    };
+    let chain = account
+        .post(&cert_url, None)
+        .await?
+        .text()
+        .await
+        .map_err(|e| format!("Failed to download certificate: {}", e))?;
+
+    // Both get written out in full before either is moved into place, so a crash partway through
+    // leaves the old pair alone. needs_renewal catches the sliver of time between the two renames.
+    let key = stage_private(
+        &config.key_path(),
+        cert.serialize_private_key_pem().as_bytes(),
+    )?;
+    let chain = stage_private(&config.cert_path(), chain.as_bytes())?;
+    put_in_place(&key, &config.key_path())?;
+    put_in_place(&chain, &config.cert_path())
+}
+
+/// Whether the private key in key_path goes with this public key (DER SubjectPublicKeyInfo).
+fn key_matches(key_path: &Path, public_key: &[u8]) -> bool {
+    fs::read_to_string(key_path)
+        .ok()
+        .and_then(|pem| rcgen::KeyPair::from_pem(&pem).ok())
+        .is_some_and(|key| key.public_key_der() == public_key)
+}
+
+fn location(response: &reqwest::Response) -> Result<String, String> {
+    response
+        .headers()
+        .get(header::LOCATION)
+        .and_then(|l| l.to_str().ok())
+        .map(str::to_owned)
+        .ok_or(String::from("ACME server didn't send a Location!"))
+}
+
+fn replay_nonce(response: &reqwest::Response) -> Option<String> {
+    response
+        .headers()
+        .get("replay-nonce")
+        .and_then(|n| n.to_str().ok())
+        .map(str::to_owned)
+}
+
+/// Writes keys and certs through a temporary file with tight permissions.
+fn write_private(path: &Path, contents: &[u8]) -> Result<(), String> {
+    let tmp = stage_private(path, contents)?;
+    put_in_place(&tmp, path)
+}
+
+/// Writes what's meant for `path` to a temporary file next to it, and says where.
+fn stage_private(path: &Path, contents: &[u8]) -> Result<PathBuf, String> {
+    let tmp = path.with_extension("tmp");
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options
+        .open(&tmp)
+        .and_then(|mut file| file.write_all(contents).and_then(|_| file.sync_all()))
+        .map_err(|e| format!("Failed to write {}: {}", tmp.display(), e))?;
+    Ok(tmp)
+}
+
+fn put_in_place(tmp: &Path, path: &Path) -> Result<(), String> {
+    fs::rename(tmp, path).map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+}
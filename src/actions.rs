@@ -0,0 +1,229 @@
+//! The write services: things a visitor can do to the account rather than just look at,
+//! like queueing a song. Each one sits behind a per-visitor rate limit, an optional secret
+//! and an optional CAPTCHA, and every attempt ends up in the audit log.
+
+use axum::{
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+
+use parking_lot::Mutex;
+
+use serde_json::Value;
+
+use std::collections::{HashMap, VecDeque};
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::app::{disconnected_response, has_bearer};
+use crate::audit::AuditLog;
+use crate::conf::{Captcha, RateLimit, Service};
+use crate::params::Rejected;
+use crate::spotify::SpotifyClient;
+
+/// Where pages put the token their CAPTCHA widget handed them.
+pub const CAPTCHA_TOKEN: &str = "x-captcha-token";
+
+/// Remembers when each visitor last used a service, one window's worth.
+/// IPv6 visitors are counted by their /64, since anyone with one address has the rest of the block too.
+pub struct RateLimiter {
+    limit: RateLimit,
+    seen: Mutex<HashMap<IpAddr, VecDeque<Instant>>>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: RateLimit) -> RateLimiter {
+        RateLimiter {
+            limit,
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Counts a request from `ip` if it's allowed, or says how long until it would be.
+    pub fn check(&self, ip: IpAddr) -> Result<(), Duration> {
+        let now = Instant::now();
+        let per = self.limit.per;
+        let mut seen = self.seen.lock();
+        // Forget anyone who's been quiet for a whole window, so this doesn't grow forever.
+        seen.retain(|_, times| {
+            while times.front().is_some_and(|t| now.duration_since(*t) >= per) {
+                times.pop_front();
+            }
+            !times.is_empty()
+        });
+        let times = seen.entry(visitor(ip)).or_default();
+        match times.front() {
+            Some(oldest) if times.len() >= self.limit.count => {
+                Err(per.saturating_sub(now.duration_since(*oldest)))
+            }
+            _ => {
+                times.push_back(now);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Who a rate limit counts as one visitor: an IPv4 address, or an IPv6 /64.
+fn visitor(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => ip,
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => IpAddr::V6(Ipv6Addr::from(u128::from(v6) & !(u64::MAX as u128))),
+        },
+    }
+}
+
+/// What each write service keeps between requests: its own rate limit, and the audit log they all share.
+pub struct Guard {
+    pub limiter: RateLimiter,
+    pub audit: Arc<AuditLog>,
+}
+
+/// Runs a write request through the gates, sends it if it gets through, and writes down how it went.
+pub async fn serve_action(
+    spotify: &SpotifyClient,
+    service: &Service,
+    guard: &Guard,
+    ip: Option<IpAddr>,
+    path: &HashMap<String, String>,
+    query: &HashMap<String, String>,
+    headers: &HeaderMap,
+) -> Response {
+    let (outcome, resp) = attempt(spotify, service, &guard.limiter, ip, path, query, headers).await;
+    // Only what the service takes: anything else in the query is the visitor's business, not the log's.
+    let declared: HashMap<String, String> = service
+        .query
+        .iter()
+        .filter_map(|param| Some((param.name.clone(), query.get(&param.name)?.clone())))
+        .collect();
+    guard.audit.record(
+        &service.name,
+        ip,
+        &declared,
+        outcome,
+        resp.status().as_u16(),
+    );
+    (service.cors.headers(headers), resp).into_response()
+}
+
+async fn attempt(
+    spotify: &SpotifyClient,
+    service: &Service,
+    limiter: &RateLimiter,
+    ip: Option<IpAddr>,
+    path: &HashMap<String, String>,
+    query: &HashMap<String, String>,
+    headers: &HeaderMap,
+) -> (&'static str, Response) {
+    let write = match &service.write {
+        Some(write) => write,
+        None => {
+            return (
+                "not_writable",
+                StatusCode::METHOD_NOT_ALLOWED.into_response(),
+            )
+        }
+    };
+    // Without an address there's no telling visitors apart, and one bucket for all of them
+    // would let anyone use up everybody's writes.
+    let ip = match ip {
+        Some(ip) => ip,
+        None => {
+            tracing::warn!(
+                service = %service.name,
+                "Turning away a write from a client we can't place; is the proxy sending X-Forwarded-For?"
+            );
+            return ("unidentified", StatusCode::FORBIDDEN.into_response());
+        }
+    };
+    // Counted before anything else, so guessing at the secret costs the same as using it.
+    if let Err(wait) = limiter.check(ip) {
+        let retry_after = HeaderValue::from(wait.as_secs().max(1));
+        return (
+            "rate_limited",
+            (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(header::RETRY_AFTER, retry_after)],
+                "Slow down!",
+            )
+                .into_response(),
+        );
+    }
+    if let Some(secret) = &write.secret {
+        if !has_bearer(headers, secret) {
+            return ("unauthorized", StatusCode::UNAUTHORIZED.into_response());
+        }
+    }
+    if let Some(captcha) = &write.captcha {
+        let token = headers
+            .get(CAPTCHA_TOKEN)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+        if token.is_empty() || !verify_captcha(spotify, captcha, token, ip).await {
+            return ("captcha_failed", StatusCode::FORBIDDEN.into_response());
+        }
+    }
+    let endpoint = match service.endpoint_for(path, query) {
+        Ok(endpoint) => endpoint,
+        Err(Rejected::Path(e)) => return ("rejected", (StatusCode::NOT_FOUND, e).into_response()),
+        Err(Rejected::Query(e)) => {
+            return ("rejected", (StatusCode::BAD_REQUEST, e).into_response())
+        }
+    };
+    if spotify.user_tokens().tokens().is_none() {
+        return ("disconnected", disconnected_response(spotify.config()));
+    }
+    let resp = match spotify.send_action(service, &endpoint).await {
+        Ok(resp) => resp,
+        Err(e) => {
+            tracing::warn!(service = %service.name, "{}", e);
+            return ("upstream_error", StatusCode::BAD_GATEWAY.into_response());
+        }
+    };
+    match resp.status().is_success() {
+        true => ("sent", StatusCode::NO_CONTENT.into_response()),
+        false => {
+            tracing::warn!(
+                service = %service.name,
+                status = resp.status().as_u16(),
+                "Spotify turned down a write"
+            );
+            ("upstream_error", StatusCode::BAD_GATEWAY.into_response())
+        }
+    }
+}
+
+/// Asks the CAPTCHA provider whether the token's good. Anything but a clear yes is a no.
+async fn verify_captcha(
+    spotify: &SpotifyClient,
+    captcha: &Captcha,
+    token: &str,
+    ip: IpAddr,
+) -> bool {
+    let form = [
+        ("secret", captcha.secret.clone()),
+        ("response", token.to_owned()),
+        ("remoteip", ip.to_string()),
+    ];
+    match spotify
+        .http()
+        .post(&captcha.verify_url)
+        .form(&form)
+        .send()
+        .await
+    {
+        Ok(resp) => resp
+            .json::<Value>()
+            .await
+            .ok()
+            .and_then(|json| json.get("success")?.as_bool())
+            .unwrap_or(false),
+        Err(e) => {
+            tracing::warn!("Failed to check a CAPTCHA: {}", e);
+            false
+        }
+    }
+}
@@ -0,0 +1,348 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    middleware,
+    response::{IntoResponse, Redirect, Response},
+    routing::{get, post},
+    Extension, Json, Router,
+};
+
+use rand::{distributions::Alphanumeric, Rng};
+
+use reqwest::StatusCode as reqsc;
+
+use serde_json::Value;
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::actions;
+use crate::conf::{Config, Service, ServiceAuth};
+use crate::health;
+use crate::logging;
+use crate::metrics::{self, TokenKind};
+use crate::params::Rejected;
+use crate::proxy::{self, ClientInfo};
+use crate::spotify::{self, SpotifyClient};
+use crate::store;
+
+/// Tells the page whether it got a track, an episode or an ad, so it knows which embed to use.
+pub(crate) const ITEM_TYPE: &str = "x-item-type";
+
+/// Everything obscurify serves, ready to nest in another app or serve as is.
+/// Starts keeping the tokens fresh with the client credentials from the config, so it needs to be called from inside a tokio runtime.
+pub fn router(config: Config) -> Router {
+    let spotify = Arc::new(SpotifyClient::new(Arc::new(config)));
+    spotify.start();
+    router_with_client(spotify)
+}
+
+/// Same as router, for when you want to hang on to the client (or set it up yourself).
+/// Doesn't start anything; that's on you.
+pub fn router_with_client(spotify: Arc<SpotifyClient>) -> Router {
+    let config = spotify.config();
+    let mut app: Router<Arc<SpotifyClient>> = Router::new();
+    for service in config.services.iter() {
+        let preflight = service.cors.clone();
+        let service = service.clone();
+        if let Some(write) = &service.write {
+            let guard = Arc::new(actions::Guard {
+                limiter: actions::RateLimiter::new(write.rate_limit),
+                audit: config.audit.clone(),
+            });
+            app = app.route(
+                service.domain.clone().as_str(),
+                post(
+                    move |State(spotify): State<Arc<SpotifyClient>>,
+                          client: Option<Extension<ClientInfo>>,
+                          Path(path): Path<HashMap<String, String>>,
+                          Query(query): Query<HashMap<String, String>>,
+                          headers: HeaderMap| {
+                        let (service, guard) = (service.clone(), guard.clone());
+                        let ip = client.and_then(|Extension(client)| client.ip);
+                        async move {
+                            actions::serve_action(
+                                &spotify, &service, &guard, ip, &path, &query, &headers,
+                            )
+                            .await
+                        }
+                    },
+                )
+                .options(move |headers: HeaderMap| async move {
+                    preflight.preflight(&headers, "POST, OPTIONS")
+                }),
+            );
+            continue;
+        }
+        app = app.route(
+            service.domain.clone().as_str(),
+            get(
+                move |State(spotify): State<Arc<SpotifyClient>>,
+                      Path(path): Path<HashMap<String, String>>,
+                      Query(query): Query<HashMap<String, String>>,
+                      headers: HeaderMap| {
+                    let service = service.clone();
+                    async move { serve_service(&spotify, &service, &path, &query, &headers).await }
+                },
+            )
+            .options(move |headers: HeaderMap| async move {
+                preflight.preflight(&headers, "GET, OPTIONS")
+            }),
+        );
+    }
+    if config.admin_secret.is_some() {
+        app = app
+            .route("/logout", post(logout))
+            .route("/reload", post(reload))
+            .route("/metrics", get(render_metrics));
+    }
+    let uses = |auth| config.services.iter().any(|service| service.auth == auth);
+    let (user_used, app_used) = (uses(ServiceAuth::User), uses(ServiceAuth::App));
+    let app = app
+        .route("/healthz", get(health::healthz))
+        .route(
+            "/readyz",
+            get(
+                move |State(spotify): State<Arc<SpotifyClient>>| async move {
+                    let mut in_use = Vec::new();
+                    if user_used {
+                        in_use.push(("user", spotify.user_tokens()));
+                    }
+                    if app_used {
+                        in_use.push(("app", spotify.app_tokens()));
+                    }
+                    health::readyz(&in_use)
+                },
+            ),
+        )
+        .route("/authenticate", get(authorize))
+        .route("/authorized", get(write_tokens));
+    let app = app.route_layer(middleware::from_fn(metrics::track));
+    // Layers run outside-in from the bottom, so the access log gets to see the ClientInfo.
+    let app = app.layer(middleware::from_fn(logging::access_log));
+    let app = app.layer(middleware::from_fn_with_state(
+        Arc::new(config.trusted_proxies.clone()),
+        proxy::client_info,
+    ));
+    app.with_state(spotify)
+}
+
+async fn serve_service(
+    spotify: &SpotifyClient,
+    service: &Service,
+    path: &HashMap<String, String>,
+    query: &HashMap<String, String>,
+    headers: &HeaderMap,
+) -> Response {
+    let cors = service.cors.headers(headers);
+    let endpoint = match service.endpoint_for(path, query) {
+        Ok(endpoint) => endpoint,
+        Err(Rejected::Path(e)) => return (StatusCode::NOT_FOUND, cors, e).into_response(),
+        Err(Rejected::Query(e)) => return (StatusCode::BAD_REQUEST, cors, e).into_response(),
+    };
+    let tokens = spotify.tokens_for(service.auth);
+    match service.auth {
+        ServiceAuth::App if tokens.tokens().is_none_or(|t| t.is_expired()) => {
+            spotify.ensure_app_token().await;
+        }
+        ServiceAuth::User if tokens.tokens().is_none() => {
+            return (cors, disconnected_response(spotify.config())).into_response();
+        }
+        _ => (),
+    }
+    let resp = match spotify.get_service(service, &endpoint).await {
+        Ok(resp) => handle_api_response(service, resp).await,
+        Err(e) => error_response(StatusCode::BAD_GATEWAY, &e),
+    };
+    (cors, resp).into_response()
+}
+
+/// Generates a new OAuth token if it doesn't exist.
+/// Writes down the refresh token, since we'll need that eventually.
+/// The tokens live in an AuthState in case two people try to load my website at the same time (unlikely!)
+async fn authorize(State(spotify): State<Arc<SpotifyClient>>) -> Response {
+    let tokens = spotify.user_tokens();
+    if tokens.tokens().is_none() && tokens.state().is_empty() {
+        tokens.set_state(new_state());
+        Redirect::to(spotify.authorization_url(&tokens.state()).as_str()).into_response()
+    } else {
+        "Access token already present! No need for further authorization. Rock on :)"
+            .into_response()
+    }
+}
+
+/// Unbinds the account: drops the tokens, stops refreshing them and deletes the stored copy.
+/// Only reachable with the admin secret as a bearer token.
+async fn logout(State(spotify): State<Arc<SpotifyClient>>, headers: HeaderMap) -> Response {
+    let config = spotify.config();
+    if !is_admin(config, &headers) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    spotify.user_tokens().clear();
+    if let Some(path) = &config.token_store {
+        if let Err(e) = store::delete(path) {
+            tracing::error!(path = %path.display(), "Failed to delete tokens: {}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    }
+    "Logged out.".into_response()
+}
+
+/// Picks up tokens `obscurify auth` just wrote to the store. Admin only, like /logout.
+async fn reload(State(spotify): State<Arc<SpotifyClient>>, headers: HeaderMap) -> Response {
+    if !is_admin(spotify.config(), &headers) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    match spotify.reload_tokens() {
+        Ok(true) => "Reloaded tokens.".into_response(),
+        Ok(false) => (StatusCode::NOT_FOUND, "No stored tokens to reload.").into_response(),
+        Err(e) => {
+            tracing::error!("{}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// The Prometheus metrics. They say more about the account than visitors need to know,
+/// so these want the admin secret too (a scraper's bearer_token).
+async fn render_metrics(State(spotify): State<Arc<SpotifyClient>>, headers: HeaderMap) -> Response {
+    if !is_admin(spotify.config(), &headers) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    metrics::render(&[
+        (TokenKind::User, spotify.user_tokens()),
+        (TokenKind::App, spotify.app_tokens()),
+    ])
+    .into_response()
+}
+
+/// Whether the request came with the admin secret as a bearer token.
+fn is_admin(config: &Config, headers: &HeaderMap) -> bool {
+    config
+        .admin_secret
+        .as_deref()
+        .is_some_and(|secret| has_bearer(headers, secret))
+}
+
+/// Whether the request's bearer token is `secret`. An empty secret lets nobody in.
+pub(crate) fn has_bearer(headers: &HeaderMap, secret: &str) -> bool {
+    let presented = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .unwrap_or_default();
+    !secret.is_empty() && constant_time_eq(presented.as_bytes(), secret.as_bytes())
+}
+
+/// Compares secrets without bailing out at the first differing byte.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// For when Spotify let us down: the details go in the log, the visitor just gets the status.
+fn error_response(status: StatusCode, e: &str) -> Response {
+    tracing::warn!("{}", e);
+    status.into_response()
+}
+
+/// What the user-token services say while nobody's logged in.
+pub(crate) fn disconnected_response(config: &Config) -> Response {
+    (
+        StatusCode::from_u16(config.disconnected.status).unwrap_or(StatusCode::SERVICE_UNAVAILABLE),
+        config.disconnected.body.clone(),
+    )
+        .into_response()
+}
+
+/// Turns Spotify's answer into the bare value the service extracts, along with what kind of item it came from.
+/// Nothing to extract (an ad's playing, say) is a 204, same as nothing playing at all.
+async fn handle_api_response(service: &Service, resp: reqwest::Response) -> Response {
+    match resp.status() {
+        reqsc::NO_CONTENT => StatusCode::NO_CONTENT.into_response(),
+        reqsc::OK => {
+            let json = match resp.json::<Value>().await {
+                Ok(json) => json,
+                Err(e) => {
+                    return error_response(
+                        StatusCode::BAD_GATEWAY,
+                        &format!("Failed to parse Spotify's response: {}", e),
+                    )
+                }
+            };
+            let mut headers = HeaderMap::new();
+            if let Some(item_type) = item_type(&json).and_then(|t| HeaderValue::from_str(t).ok()) {
+                headers.insert(ITEM_TYPE, item_type);
+            }
+            match spotify::retrieve_json_value(
+                &json,
+                &service.extract.split("/").collect::<Vec<&str>>(),
+            ) {
+                // Strings go out as plain text; anything else stays JSON, so lists and objects still parse.
+                Some(Value::String(text)) => (headers, text.clone()).into_response(),
+                Some(value) => (headers, Json(value)).into_response(),
+                None => (StatusCode::NO_CONTENT, headers).into_response(),
+            }
+        }
+        _ => StatusCode::INTERNAL_SERVER_ERROR.into_response(), // don't worry about it
+    }
+}
+
+/// What a response is about: the player says outright, single items carry a type,
+/// and otherwise we don't know.
+fn item_type(json: &Value) -> Option<&str> {
+    json.get("currently_playing_type")
+        .or_else(|| json.pointer("/item/type"))
+        .or_else(|| json.get("type"))
+        .and_then(Value::as_str)
+}
+
+/// Serves as our final step in the Spotify authorization flow.
+/// Redeems the code we get back from authorize() and logs the server in with the tokens.
+async fn write_tokens(
+    State(spotify): State<Arc<SpotifyClient>>,
+    query: Option<Query<HashMap<String, String>>>,
+) -> Response {
+    let query = query.map(|Query(query)| query).unwrap_or_default();
+    let tokens = spotify.user_tokens();
+    let expected = tokens.state();
+    match query.get("state") {
+        None => return (StatusCode::BAD_REQUEST, "No state in the redirect!").into_response(),
+        Some(state)
+            if expected.is_empty() || !constant_time_eq(state.as_bytes(), expected.as_bytes()) =>
+        {
+            return (StatusCode::BAD_REQUEST, "The state doesn't match!").into_response()
+        }
+        Some(_) => (),
+    }
+    let code = match query.get("code") {
+        Some(code) => code,
+        None => {
+            let error = query
+                .get("error")
+                .map_or("No code in the redirect!", String::as_str);
+            return (StatusCode::BAD_REQUEST, error.to_owned()).into_response();
+        }
+    };
+
+    match spotify.redeem_code(code).await {
+        Ok(token_set) => {
+            spotify.log_in(token_set);
+            // Spent: a replayed redirect shouldn't get anywhere.
+            tokens.set_state(new_state());
+            "Successfully authorized! You can close this page now.".into_response()
+        }
+        Err(e) => {
+            tracing::error!("{}", e);
+            (StatusCode::BAD_GATEWAY, e).into_response()
+        }
+    }
+}
+
+/// Something random enough that nobody can guess the state we're waiting for.
+fn new_state() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(64)
+        .map(char::from)
+        .collect()
+}
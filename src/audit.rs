@@ -0,0 +1,92 @@
+use parking_lot::Mutex;
+
+use serde::Serialize;
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// How much of each parameter gets written down. Plenty for a track URI, not much for anything else.
+pub const MAX_VALUE: usize = 128;
+
+/// One attempt at a write service, however far it got.
+#[derive(Serialize)]
+pub struct Entry<'a> {
+    /// Seconds since the Unix epoch.
+    pub at: u64,
+    pub service: &'a str,
+    pub ip: Option<IpAddr>,
+    /// The service's query parameters as the visitor sent them, before any checks,
+    /// each cut down to MAX_VALUE characters.
+    pub params: &'a HashMap<String, String>,
+    /// What became of it: `sent`, `rate_limited`, `rejected` and so on.
+    pub outcome: &'static str,
+    /// What we answered with.
+    pub status: u16,
+}
+
+/// Where write attempts get written down. Always the `audit` log target,
+/// plus a JSON-lines file if [audit] has a path, for keeping longer than the logs do.
+pub struct AuditLog {
+    file: Option<Mutex<File>>,
+}
+
+impl AuditLog {
+    /// Opens (or creates) the file for appending.
+    pub fn open(path: Option<&Path>) -> Result<AuditLog, String> {
+        let file = match path {
+            Some(path) => Some(Mutex::new(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .map_err(|e| format!("Failed to open audit log {}: {}", path.display(), e))?,
+            )),
+            None => None,
+        };
+        Ok(AuditLog { file })
+    }
+
+    pub fn record(
+        &self,
+        service: &str,
+        ip: Option<IpAddr>,
+        params: &HashMap<String, String>,
+        outcome: &'static str,
+        status: u16,
+    ) {
+        let params: HashMap<String, String> = params
+            .iter()
+            .map(|(name, value)| (name.clone(), value.chars().take(MAX_VALUE).collect()))
+            .collect();
+        let entry = Entry {
+            at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            service,
+            ip,
+            params: &params,
+            outcome,
+            status,
+        };
+        let line = serde_json::to_string(&entry).unwrap_or_default();
+        tracing::info!(
+            target: "audit",
+            service,
+            ip = ?ip,
+            outcome,
+            status,
+            params = %serde_json::to_string(&params).unwrap_or_default(),
+            "Write attempted"
+        );
+        if let Some(file) = &self.file {
+            if let Err(e) = writeln!(file.lock(), "{}", line) {
+                tracing::error!("Failed to write to the audit log: {}", e);
+            }
+        }
+    }
+}
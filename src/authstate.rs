@@ -1,37 +1,143 @@
-use parking_lot::Mutex;
+use parking_lot::{Mutex, RwLock};
+use serde::{Deserialize, Deserializer};
 
-pub enum Token {
-    AccessToken,
-    RefreshToken,
-    TokenDuration,
-    StateToken,
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use tokio::task::JoinHandle;
+
+/// How long before expiry we go and fetch a new access token.
+const REFRESH_MARGIN: Duration = Duration::from_secs(300);
+
+/// Everything the token endpoint hands back to us, deserialized straight off the wire.
+/// Spotify sends `scope` as a space-separated string and `expires_in` as seconds from now,
+/// so both get massaged into something more useful on the way in.
+#[derive(Debug, Clone, Deserialize)]
+pub struct TokenSet {
+    pub access_token: String,
+    /// Refreshes don't always come with a new refresh token, so this may be missing.
+    #[serde(default)]
+    pub refresh_token: Option<String>,
+    #[serde(rename = "scope", default, deserialize_with = "split_scopes")]
+    pub scopes: Vec<String>,
+    #[serde(rename = "expires_in", deserialize_with = "expires_at_from_now")]
+    pub expires_at: SystemTime,
+}
+
+impl TokenSet {
+    pub fn is_expired(&self) -> bool {
+        self.expires_at <= SystemTime::now()
+    }
+
+    /// How long we can sit on this token before it needs refreshing.
+    pub fn refresh_in(&self) -> Duration {
+        self.expires_at
+            .checked_sub(REFRESH_MARGIN)
+            .and_then(|at| at.duration_since(SystemTime::now()).ok())
+            .unwrap_or(Duration::ZERO)
+    }
+}
+
+fn split_scopes<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    Ok(Option::<String>::deserialize(deserializer)?
+        .unwrap_or_default()
+        .split_whitespace()
+        .map(str::to_owned)
+        .collect())
 }
 
-#[derive(Debug)]
+fn expires_at_from_now<'de, D: Deserializer<'de>>(deserializer: D) -> Result<SystemTime, D::Error> {
+    Ok(SystemTime::now() + Duration::from_secs(u64::deserialize(deserializer)?))
+}
+
+/// The tokens live behind a single lock and get swapped out wholesale,
+/// so nobody ever reads a fresh access token next to a stale refresh token.
+#[derive(Debug, Default)]
 pub struct AuthState {
-    pub access_token: Mutex<String>,
-    pub refresh_token: Mutex<String>,
-    pub token_duration: Mutex<String>,
-    pub state_state: Mutex<String>,
+    tokens: RwLock<Option<Arc<TokenSet>>>,
+    state_state: Mutex<String>,
+    refresher: Mutex<Option<JoinHandle<()>>>,
+    /// Whether the last refresh worked; None until one's been tried.
+    last_refresh: Mutex<Option<bool>>,
+    /// What Spotify said to the last API call made with these tokens.
+    last_upstream: Mutex<Option<u16>>,
+    /// Whether the tokens have changed since they were last written to the store.
+    unsaved: Mutex<bool>,
 }
 
 impl AuthState {
-    pub fn retrieve(&self, t: Token) -> String {
-        return match t {
-            Token::AccessToken => self.access_token.lock(),
-            Token::RefreshToken => self.refresh_token.lock(),
-            Token::TokenDuration => self.token_duration.lock(),
-            Token::StateToken => self.state_state.lock(),
+    pub fn tokens(&self) -> Option<Arc<TokenSet>> {
+        self.tokens.read().clone()
+    }
+
+    pub fn access_token(&self) -> Option<String> {
+        self.tokens.read().as_ref().map(|t| t.access_token.clone())
+    }
+
+    /// Replaces the current token set, carrying the old refresh token over if the new set didn't come with one.
+    pub fn swap(&self, mut new: TokenSet) {
+        let mut tokens = self.tokens.write();
+        if new.refresh_token.is_none() {
+            new.refresh_token = tokens.as_ref().and_then(|t| t.refresh_token.clone());
         }
-        .to_string();
+        *tokens = Some(Arc::new(new));
+        *self.unsaved.lock() = true;
+    }
+
+    pub fn unsaved(&self) -> bool {
+        *self.unsaved.lock()
+    }
+
+    /// For once the store has the current tokens (or never should).
+    pub fn mark_saved(&self) {
+        *self.unsaved.lock() = false;
+    }
+
+    /// Forgets everything: tokens, any half-finished authorization, and the refresh loop.
+    pub fn clear(&self) {
+        if let Some(refresher) = self.refresher.lock().take() {
+            refresher.abort();
+        }
+        *self.tokens.write() = None;
+        self.state_state.lock().clear();
+        *self.last_refresh.lock() = None;
+        *self.last_upstream.lock() = None;
+        *self.unsaved.lock() = false;
+    }
+
+    /// Hands over the task keeping these tokens fresh, stopping whichever one was running before.
+    pub fn set_refresher(&self, refresher: JoinHandle<()>) {
+        if let Some(old) = self.refresher.lock().replace(refresher) {
+            old.abort();
+        }
+    }
+
+    /// A successful refresh also forgets the last upstream status, so a 401 from the old token
+    /// doesn't keep readiness down once there's a new one.
+    pub fn record_refresh(&self, succeeded: bool) {
+        *self.last_refresh.lock() = Some(succeeded);
+        if succeeded {
+            *self.last_upstream.lock() = None;
+        }
+    }
+
+    pub fn last_refresh(&self) -> Option<bool> {
+        *self.last_refresh.lock()
+    }
+
+    pub fn record_upstream(&self, status: u16) {
+        *self.last_upstream.lock() = Some(status);
+    }
+
+    pub fn last_upstream(&self) -> Option<u16> {
+        *self.last_upstream.lock()
+    }
+
+    pub fn state(&self) -> String {
+        self.state_state.lock().clone()
     }
 
-    pub fn write(&self, t: Token, s: String) {
-        *match t {
-            Token::AccessToken => self.access_token.lock(),
-            Token::RefreshToken => self.refresh_token.lock(),
-            Token::TokenDuration => self.token_duration.lock(),
-            Token::StateToken => self.state_state.lock(),
-        } = s;
+    pub fn set_state(&self, s: String) {
+        *self.state_state.lock() = s;
     }
 }
@@ -0,0 +1,252 @@
+use axum::{extract::Query, routing::get, Router};
+
+use rand::{distributions::Alphanumeric, Rng};
+
+use reqwest::Url;
+
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, Notify};
+
+use crate::conf::Config;
+use crate::spotify;
+use crate::store;
+
+/// Runs the authorization code flow from a terminal, for servers that aren't reachable yet.
+/// If the redirect URI points at loopback we catch the redirect ourselves;
+/// otherwise the user pastes the URL they got bounced to (or just the code) back in.
+/// Either way the tokens end up in the configured store, and a running server gets asked to pick them up.
+/// That needs the admin secret; without one we won't go behind a running server's back,
+/// since it would write its own tokens over ours.
+pub async fn auth(config: &Config) -> Result<(), String> {
+    let path = config
+        .token_store
+        .as_ref()
+        .ok_or("No token store configured! Add a [tokens] section with a store path.")?;
+    if config.admin_secret.is_none() && server_running(config).await {
+        return Err(format!(
+            "The server at {} is running and won't notice new tokens without an [admin] secret. \
+             Stop it first (it'll read them when it starts), or configure a secret.",
+            server_url(config, "")
+        ));
+    }
+
+    let state: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(64)
+        .map(char::from)
+        .collect();
+    let client = spotify::SpotifyClient::new(Arc::new(config.clone()));
+    let url = client.authorization_url(&state);
+
+    let redirect = Url::parse(&config.redirect).map_err(|e| e.to_string())?;
+    // Only a bare pasted code comes without a state to check; anything that came through a redirect has to match.
+    let (params, redirected) = match loopback_addr(&redirect) {
+        Some(addr) => {
+            println!("Open this URL in a browser on this machine:\n\n{}\n", url);
+            println!("Waiting for Spotify to redirect to {}...", redirect);
+            (
+                wait_for_redirect(addr, redirect.path().to_owned()).await?,
+                true,
+            )
+        }
+        None => {
+            println!("Open this URL in a browser:\n\n{}\n", url);
+            print!("Paste the URL you were redirected to (or just the code): ");
+            io::stdout().flush().map_err(|e| e.to_string())?;
+            let mut line = String::new();
+            io::stdin()
+                .lock()
+                .read_line(&mut line)
+                .map_err(|e| e.to_string())?;
+            parse_pasted(line.trim())?
+        }
+    };
+
+    if let Some(error) = params.get("error") {
+        return Err(format!("Spotify refused the authorization: {}", error));
+    }
+    match params.get("state") {
+        Some(s) if *s != state => {
+            return Err(format!(
+                "Received an incorrect state of {} when expecting {}!",
+                s, state
+            ))
+        }
+        None if redirected => return Err(String::from("No state in the redirect!")),
+        _ => (),
+    }
+    let code = params.get("code").ok_or("No code in the redirect!")?;
+
+    let token_set = client.redeem_code(code).await?;
+
+    store::save(path, &token_set)
+        .map_err(|e| format!("Failed to write tokens to {}: {}", path.display(), e))?;
+    println!(
+        "Successfully authorized! Tokens written to {}.",
+        path.display()
+    );
+
+    let secret = match &config.admin_secret {
+        Some(secret) => secret,
+        None => return Ok(()),
+    };
+    let url = server_url(config, "/reload");
+    match reqwest::Client::new()
+        .post(&url)
+        .bearer_auth(secret)
+        .send()
+        .await
+    {
+        Ok(response) if response.status().is_success() => {
+            println!("The server at {} picked them up.", url);
+            Ok(())
+        }
+        Ok(response) => Err(format!(
+            "The server at {} didn't pick up the tokens ({}); restart it before it overwrites them.",
+            url,
+            response.status()
+        )),
+        Err(_) => {
+            println!("No server running at {}; it'll read them when it starts.", url);
+            Ok(())
+        }
+    }
+}
+
+/// Where the running server would be answering `path`.
+fn server_url(config: &Config, path: &str) -> String {
+    format!(
+        "{}://{}{}",
+        if config.https.is_some() {
+            "https"
+        } else {
+            "http"
+        },
+        config.uri,
+        path
+    )
+}
+
+/// Whether anything's answering health checks where the server would be.
+async fn server_running(config: &Config) -> bool {
+    reqwest::Client::new()
+        .get(server_url(config, "/healthz"))
+        .timeout(Duration::from_secs(5))
+        .send()
+        .await
+        .is_ok()
+}
+
+/// Where to listen for the redirect ourselves, if the redirect URI is on this machine.
+fn loopback_addr(redirect: &Url) -> Option<SocketAddr> {
+    if redirect.scheme() != "http" {
+        return None;
+    }
+    let host = redirect
+        .host_str()?
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    let port = redirect.port_or_known_default()?;
+    match host.parse::<IpAddr>() {
+        Ok(ip) if ip.is_loopback() => Some(SocketAddr::new(ip, port)),
+        Ok(_) => None,
+        Err(_) if host == "localhost" => (host, port).to_socket_addrs().ok()?.next(),
+        Err(_) => None,
+    }
+}
+
+/// Stands up a throwaway listener on the redirect URI and hands back whatever Spotify sends it.
+async fn wait_for_redirect(
+    addr: SocketAddr,
+    path: String,
+) -> Result<HashMap<String, String>, String> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .map_err(|e| format!("Failed to listen on {}: {}", addr, e))?;
+    let (tx, mut rx) = mpsc::channel(1);
+    let app = Router::new().route(
+        path.as_str(),
+        get(
+            move |Query(params): Query<HashMap<String, String>>| async move {
+                let _ = tx.send(params).await;
+                "Got it! You can close this page and head back to your terminal."
+            },
+        ),
+    );
+
+    let done = Arc::new(Notify::new());
+    let shutdown = done.clone();
+    let server = tokio::spawn(async move {
+        axum::serve(listener, app)
+            .with_graceful_shutdown(async move { shutdown.notified().await })
+            .await
+    });
+
+    let params = rx.recv().await.ok_or("Redirect listener went away!")?;
+    done.notify_one();
+    let _ = server.await;
+    Ok(params)
+}
+
+/// Pulls the query parameters out of a pasted redirect URL, or treats the whole thing as a bare code.
+/// Says which it was, since only a redirect URL has a state to check.
+fn parse_pasted(pasted: &str) -> Result<(HashMap<String, String>, bool), String> {
+    if pasted.is_empty() {
+        return Err(String::from("Nothing pasted!"));
+    }
+    match Url::parse(pasted) {
+        Ok(url) => Ok((url.query_pairs().into_owned().collect(), true)),
+        Err(_) => Ok((
+            HashMap::from([(String::from("code"), pasted.to_owned())]),
+            false,
+        )),
+    }
+}
+
+/// Deletes the stored tokens, then asks the running server to drop its copy too.
+/// That second part needs the admin secret; without it the server keeps going until it restarts.
+pub async fn logout(config: &Config) -> Result<(), String> {
+    if let Some(path) = &config.token_store {
+        store::delete(path)
+            .map_err(|e| format!("Failed to delete tokens at {}: {}", path.display(), e))?;
+        println!("Deleted stored tokens at {}.", path.display());
+    }
+
+    let secret = match &config.admin_secret {
+        Some(secret) => secret,
+        None => {
+            println!("No [admin] secret configured, so a running server will stay logged in until it restarts.");
+            return Ok(());
+        }
+    };
+    let url = server_url(config, "/logout");
+    match reqwest::Client::new()
+        .post(&url)
+        .bearer_auth(secret)
+        .send()
+        .await
+    {
+        Ok(response) if response.status().is_success() => {
+            println!("Logged out the server at {}.", url);
+            Ok(())
+        }
+        Ok(response) => Err(format!(
+            "The server at {} refused to log out: {}",
+            url,
+            response.status()
+        )),
+        Err(e) => {
+            println!(
+                "Couldn't reach a running server at {} ({}); nothing more to do.",
+                url, e
+            );
+            Ok(())
+        }
+    }
+}
@@ -1,36 +1,306 @@
 use configparser::ini::Ini;
 use pico_args;
-use std::collections::HashMap;
-use std::net::{IpAddr, Ipv4Addr};
-use std::path::PathBuf;
+use std::collections::{BTreeSet, HashMap};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::audit::AuditLog;
+use crate::authstate::TokenSet;
+use crate::cors::{AllowedOrigin, CorsPolicy};
+use crate::params::{self, ParamRule, PathParam, QueryParam, Rejected};
+use crate::proxy::TrustedProxy;
+use crate::spotify;
 
 #[derive(Clone)]
 pub struct Config {
     pub https: Option<HTTPSConfig>,
-    pub routing: HashMap<String, (IpAddr, u16)>,
-    pub service: Service,
+    pub acme: Option<AcmeConfig>,
+    pub routing: HashMap<String, Vec<SocketAddr>>,
+    pub unix: Option<UnixConfig>,
+    pub trusted_proxies: Vec<TrustedProxy>,
+    pub uri: String,
+    pub redirect: String,
+    pub services: Vec<Service>,
+    pub token_store: Option<PathBuf>,
+    /// Where every write a visitor tries gets written down. Opened here, so a bad [audit] path
+    /// fails the config rather than the first write.
+    pub audit: Arc<AuditLog>,
+    pub admin_secret: Option<String>,
+    pub disconnected: Disconnected,
+    pub logging: LoggingConfig,
+    pub spotify: SpotifyConfig,
+}
+
+/// Where Spotify lives. Only worth changing to point at a mock.
+#[derive(Clone)]
+pub struct SpotifyConfig {
+    pub api_base: String,
+    pub accounts_base: String,
+    pub fixtures: Option<FixtureMode>,
+    /// client_id and client_secret from [spotify], or else what's in the `credentials` file.
+    pub credentials: spotify::Credentials,
+}
+
+/// For chasing bugs with real payloads: write every API response to a directory, or serve them back from one.
+#[derive(Clone)]
+pub enum FixtureMode {
+    Record(PathBuf),
+    /// Nothing goes upstream at all; even tokens are made up.
+    Replay(PathBuf),
+}
+
+impl SpotifyConfig {
+    pub fn replaying(&self) -> bool {
+        matches!(self.fixtures, Some(FixtureMode::Replay(_)))
+    }
+}
+
+/// How chatty to be, and in what shape.
+#[derive(Clone)]
+pub struct LoggingConfig {
+    /// Anything RUST_LOG takes, e.g. `info` or `obscurify=debug,warn`.
+    pub level: String,
+    pub format: LogFormat,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// One human-readable line per event.
+    Pretty,
+    /// One JSON object per line, for shipping off somewhere.
+    Json,
+}
+
+/// What the user-token services answer with while nobody's logged in.
+#[derive(Clone)]
+pub struct Disconnected {
+    pub status: u16,
+    pub body: String,
+}
+
+impl Config {
+    /// Every scope any configured service needs, deduplicated.
+    pub fn requested_scopes(&self) -> Vec<&str> {
+        self.services
+            .iter()
+            .flat_map(|service| service.scopes.iter().map(String::as_str))
+            .collect::<BTreeSet<&str>>()
+            .into_iter()
+            .collect()
+    }
+
+    /// Complains about any service the granted scopes won't let us serve.
+    /// Spotify will just 403 those at request time, which is a lot less obvious.
+    pub fn warn_on_missing_scopes(&self, token_set: &TokenSet) {
+        for service in self.services.iter() {
+            let missing = service.missing_scopes(&token_set.scopes);
+            if !missing.is_empty() {
+                tracing::warn!(
+                    service = %service.name,
+                    route = %service.domain,
+                    missing = %missing.join(" "),
+                    "Service needs scopes that weren't granted"
+                );
+            }
+        }
+    }
+}
+
+/// What we were asked to do on the command line.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    /// Run the server (the default).
+    Serve,
+    /// Walk through the authorization flow from a terminal and write the tokens to the store.
+    Auth,
+    /// Forget the stored tokens and tell a running server to do the same.
+    Logout,
 }
 #[derive(Clone)]
 pub struct Service {
+    pub name: String,
     pub domain: String,
     pub target: String,
     pub endpoint: String,
     pub extract: String,
-    pub uri: String,
-    pub redirect: String,
+    pub scopes: Vec<String>,
+    pub auth: ServiceAuth,
+    pub cors: CorsPolicy,
+    /// Query parameters clients may pass through to the endpoint, sorted by name.
+    pub query: Vec<QueryParam>,
+    /// The `:name`s in the domain that fill in the endpoint's `{name}`s.
+    pub path: Vec<PathParam>,
+    /// Follow `next` links and gather up to this many items before extracting.
+    pub paginate: Option<usize>,
+    /// Set for the opt-in POST services that change something on Spotify's end.
+    pub write: Option<WriteAction>,
 }
 
+/// What a write service needs on top of a read one: which method Spotify wants,
+/// and everything standing between a visitor and our account.
+#[derive(Clone)]
+pub struct WriteAction {
+    pub method: reqwest::Method,
+    pub rate_limit: RateLimit,
+    /// Callers have to send this as a bearer token.
+    pub secret: Option<String>,
+    pub captcha: Option<Captcha>,
+}
+
+/// At most `count` requests per client IP in any `per`.
+#[derive(Clone, Copy, Debug)]
+pub struct RateLimit {
+    pub count: usize,
+    pub per: Duration,
+}
+
+impl FromStr for RateLimit {
+    type Err = String;
+
+    /// `5/3600` is five an hour.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (count, per) = s.split_once('/').ok_or(format!(
+            "Invalid rate limit {} (expected count/seconds)!",
+            s
+        ))?;
+        match (usize::from_str(count.trim()), u64::from_str(per.trim())) {
+            (Ok(count), Ok(per)) if count > 0 && per > 0 => Ok(RateLimit {
+                count,
+                per: Duration::from_secs(per),
+            }),
+            _ => Err(format!(
+                "Invalid rate limit {} (expected count/seconds)!",
+                s
+            )),
+        }
+    }
+}
+
+/// Any CAPTCHA that checks tokens the hCaptcha/Turnstile/reCAPTCHA way:
+/// POST the secret and the visitor's token to a siteverify URL, get `{"success": true}` back.
+#[derive(Clone)]
+pub struct Captcha {
+    pub verify_url: String,
+    pub secret: String,
+}
+
+/// Whose token a service calls Spotify with.
+/// App services use the client-credentials flow, so they work before anyone has logged in,
+/// but they can only see public catalog data.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ServiceAuth {
+    User,
+    App,
+}
+
+impl Service {
+    /// Which of this service's scopes the given grant doesn't cover.
+    pub fn missing_scopes(&self, granted: &[String]) -> Vec<&str> {
+        self.scopes
+            .iter()
+            .filter(|scope| !granted.contains(scope))
+            .map(String::as_str)
+            .collect()
+    }
+
+    /// The endpoint as this request fills it in: path parameters put in place,
+    /// and whatever query parameters it's allowed to add tacked on.
+    pub fn endpoint_for(
+        &self,
+        path: &HashMap<String, String>,
+        incoming: &HashMap<String, String>,
+    ) -> Result<String, Rejected> {
+        let endpoint =
+            params::fill_path(&self.endpoint, &self.path, path).map_err(Rejected::Path)?;
+        // A write with a parameter left off isn't something to guess at.
+        if self.write.is_some() {
+            if let Some(missing) = self
+                .query
+                .iter()
+                .find(|param| param.default.is_none() && !incoming.contains_key(&param.name))
+            {
+                return Err(Rejected::Query(format!("{} is required", missing.name)));
+            }
+        }
+        let forwarded = params::forward(&self.query, incoming).map_err(Rejected::Query)?;
+        if forwarded.is_empty() {
+            return Ok(endpoint);
+        }
+        let query = forwarded
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect::<Vec<_>>()
+            .join("&");
+        Ok(match endpoint.contains('?') {
+            true => format!("{}&{}", endpoint, query),
+            false => format!("{}?{}", endpoint, query),
+        })
+    }
+}
+
+/// A Unix socket to serve on, for sitting behind a reverse proxy on the same box.
+#[derive(Clone)]
+pub struct UnixConfig {
+    pub path: PathBuf,
+    /// Permissions for the socket file, so the proxy's user can get at it.
+    pub mode: Option<u32>,
+}
+
+/// Lets obscurify get its own certificates over HTTP-01 instead of reading someone else's PEM files.
+#[derive(Clone)]
+pub struct AcmeConfig {
+    pub domains: Vec<String>,
+    pub contact: Vec<String>,
+    pub directory: String,
+    pub cache: PathBuf,
+    /// Extra root to trust when talking to the ACME server, e.g. Pebble's self-signed one.
+    pub ca_cert: Option<PathBuf>,
+}
+
+impl AcmeConfig {
+    pub fn cert_path(&self) -> PathBuf {
+        self.cache.join("cert.pem")
+    }
+
+    pub fn key_path(&self) -> PathBuf {
+        self.cache.join("key.pem")
+    }
+}
+
+const LETS_ENCRYPT: &str = "https://acme-v02.api.letsencrypt.org/directory";
+
 #[derive(Clone)]
 pub struct HTTPSConfig {
     pub cert: PathBuf,
     pub key: PathBuf,
+    /// Where certbot (or similar) drops HTTP-01 challenges, if we should keep serving them over plain HTTP.
+    pub acme_webroot: Option<PathBuf>,
 }
 
-const HELP: &str = "get a job";
+/// How often one visitor gets to use a write service when it doesn't say: ten an hour.
+const DEFAULT_RATE_LIMIT: RateLimit = RateLimit {
+    count: 10,
+    per: Duration::from_secs(3600),
+};
+
+/// How many items a paginated service gathers when it doesn't say.
+/// Playlists come 100 to a page, so this is five round trips at most.
+const DEFAULT_MAX_ITEMS: usize = 500;
 
-pub fn parse_args_and_render_config() -> Result<Config, String> {
-    let mut config = Ini::new();
+const HELP: &str = "\
+usage: obscurify [serve|auth|logout] [CONFIG]
+
+  serve   run the server (default)
+  auth    authorize from a terminal and write the tokens to the [tokens] store
+  logout  delete the stored tokens and log the running server out
+
+CONFIG defaults to ./obsc.conf
+";
+
+pub fn parse_args_and_render_config() -> Result<(Command, Config), String> {
     let mut pargs = pico_args::Arguments::from_env();
 
     // Help has a higher priority and should be handled separately.
@@ -39,83 +309,591 @@ pub fn parse_args_and_render_config() -> Result<Config, String> {
         std::process::exit(0);
     }
 
-    let map = config.load(match pargs.free_from_str() {
+    let (command, path) = match pargs.subcommand().map_err(|e| e.to_string())? {
+        Some(cmd) if cmd == "serve" => (Command::Serve, None),
+        Some(cmd) if cmd == "auth" => (Command::Auth, None),
+        Some(cmd) if cmd == "logout" => (Command::Logout, None),
+        // No subcommand, just the config file.
+        other => (Command::Serve, other),
+    };
+
+    let path = match path.map_or_else(|| pargs.free_from_str(), Ok) {
         Ok(path) => path,
         _ => String::from("./obsc.conf"),
-    })?;
+    };
+    Ok((command, load(&path)?))
+}
+
+/// Reads and checks a config file, for when there's no command line to take it from.
+pub fn load(path: &str) -> Result<Config, String> {
+    let map = Ini::new().load(path)?;
+
+    let acme = match map.get("acme") {
+        Some(data) => Some(AcmeConfig {
+            domains: list(data.get("domains"))
+                .filter(|domains| !domains.is_empty())
+                .ok_or("ACME needs some domains!")?,
+            contact: list(data.get("contact")).unwrap_or_default(),
+            directory: match data.get("directory") {
+                Some(Some(directory)) => directory.trim().to_owned(),
+                _ => String::from(LETS_ENCRYPT),
+            },
+            cache: match data.get("cache") {
+                Some(Some(cache)) => PathBuf::from(cache.trim()),
+                _ => return Err(String::from("ACME needs a cache directory!")),
+            },
+            ca_cert: match data.get("ca_cert") {
+                Some(Some(ca)) => Some(PathBuf::from(ca.trim())),
+                _ => None,
+            },
+        }),
+        None => None,
+    };
 
     let out = Config {
-        https: match map.get("https") {
-            Some(data) => Some(HTTPSConfig {
-                cert: PathBuf::from(data.get("cert").unwrap().as_ref().unwrap().trim()),
-                key: PathBuf::from(data.get("key").unwrap().as_ref().unwrap().trim()),
+        // ACME-managed certificates win over hand-provisioned ones.
+        https: match (&acme, map.get("https")) {
+            (Some(acme), _) => Some(HTTPSConfig {
+                cert: acme.cert_path(),
+                key: acme.key_path(),
+                acme_webroot: None,
+            }),
+            (None, Some(data)) => Some(HTTPSConfig {
+                cert: PathBuf::from(required(data, "cert", "[https]")?),
+                key: PathBuf::from(required(data, "key", "[https]")?),
+                acme_webroot: data
+                    .get("acme_webroot")
+                    .and_then(|webroot| webroot.as_ref())
+                    .map(|webroot| PathBuf::from(webroot.trim())),
             }),
-            None => None,
+            (None, None) => None,
         },
+        acme,
+        unix: match map.get("routing").and_then(|data| data.get("unix")) {
+            Some(Some(path)) => Some(UnixConfig {
+                path: PathBuf::from(path.trim()),
+                mode: match map.get("routing").and_then(|data| data.get("unix_mode")) {
+                    Some(Some(mode)) => Some(
+                        u32::from_str_radix(mode.trim(), 8)
+                            .map_err(|_| format!("Invalid unix_mode {}!", mode))?,
+                    ),
+                    _ => None,
+                },
+            }),
+            _ => None,
+        },
+        trusted_proxies: list(
+            map.get("routing")
+                .and_then(|data| data.get("trusted_proxies")),
+        )
+        .unwrap_or_default()
+        .iter()
+        .map(|proxy| TrustedProxy::from_str(proxy))
+        .collect::<Result<_, _>>()?,
         routing: match map.get("routing") {
-            Some(data) => data
-                .into_iter()
-                .filter_map(|(key, value)| match value {
-                    Some(ip) => match ip.split_once(":") {
-                        Some((subip, port)) => Some((
-                            key,
-                            (
-                                Ipv4Addr::from_str(subip).unwrap(),
-                                u16::from_str_radix(port, 10),
-                            ),
-                        )),
-
-                        None => match key.as_str() {
-                            "http" => {
-                                Some((key, (Ipv4Addr::from_str(ip).unwrap(), u16::from_str("80"))))
-                            }
-                            "https" => {
-                                Some((key, (Ipv4Addr::from_str(ip).unwrap(), u16::from_str("443"))))
-                            }
-                            _ => None,
-                        },
-                    },
-                    None => None,
-                })
-                .fold(HashMap::new(), move |mut a, b| {
-                    a.insert(
-                        b.0.to_owned(),
-                        match b.1 {
-                            (ip, res) => (IpAddr::V4(ip), res.unwrap()),
-                        },
-                    );
-                    a
-                }),
+            Some(data) => {
+                let mut routing = HashMap::new();
+                for (key, value) in data.iter() {
+                    let default_port = match key.as_str() {
+                        "http" => 80,
+                        "https" => 443,
+                        _ => continue,
+                    };
+                    if let Some(value) = value {
+                        routing.insert(key.to_owned(), parse_listen_addrs(value, default_port)?);
+                    }
+                }
+                routing
+            }
             None => return Err(String::from("No routing configuration!")),
         },
-        service: match map.get("service") {
-            Some(svc) => Service {
-                domain: svc
-                    .get("domain")
-                    .expect("Services need domains, too!")
-                    .to_owned()
-                    .unwrap(),
-                target: svc.get("target").expect("And targets!").to_owned().unwrap(),
-                endpoint: svc
-                    .get("endpoint")
-                    .expect("And also endpoints")
-                    .to_owned()
-                    .unwrap(),
-                redirect: svc
-                    .get("redirect")
-                    .expect("And redirects!")
-                    .to_owned()
-                    .unwrap(),
-                uri: svc.get("uri").expect("And a URI!").to_owned().unwrap(),
-                extract: svc
-                    .get("extract")
-                    .expect("And extracted JSON!")
-                    .to_owned()
-                    .unwrap(),
+        uri: match map.get("service") {
+            Some(svc) => required(svc, "uri", "[service]")?,
+            None => return Err(String::from("No services specified!")),
+        },
+        redirect: match map.get("service") {
+            Some(svc) => required(svc, "redirect", "[service]")?,
+            None => return Err(String::from("No services specified!")),
+        },
+        services: map
+            .iter()
+            .filter_map(|(section, svc)| {
+                let name = match section.as_str() {
+                    "service" if svc.contains_key("domain") => section.as_str(),
+                    other => other.strip_prefix("service.")?,
+                };
+                Some(parse_service(name, svc))
+            })
+            .collect::<Result<_, _>>()?,
+        token_store: map
+            .get("tokens")
+            .and_then(|data| data.get("store"))
+            .and_then(|store| store.as_ref())
+            .map(|store| PathBuf::from(store.trim())),
+        audit: Arc::new(AuditLog::open(
+            map.get("audit")
+                .and_then(|data| data.get("path"))
+                .and_then(|path| path.as_ref())
+                .map(|path| Path::new(path.trim())),
+        )?),
+        admin_secret: map
+            .get("admin")
+            .and_then(|data| data.get("secret"))
+            .and_then(|secret| secret.as_ref())
+            .map(|secret| secret.trim().to_owned())
+            .filter(|secret| !secret.is_empty()),
+        disconnected: Disconnected {
+            status: match map
+                .get("service")
+                .and_then(|svc| svc.get("disconnected_status"))
+            {
+                Some(Some(status)) => u16::from_str(status.trim())
+                    .ok()
+                    .filter(|status| (100..600).contains(status))
+                    .ok_or(format!("Invalid disconnected_status {}!", status))?,
+                _ => 503,
+            },
+            body: match map
+                .get("service")
+                .and_then(|svc| svc.get("disconnected_body"))
+            {
+                Some(Some(body)) => body.to_owned(),
+                _ => String::from("Not connected to Spotify right now."),
+            },
+        },
+        spotify: SpotifyConfig {
+            credentials: credentials(map.get("spotify"))?,
+            api_base: match map.get("spotify").and_then(|data| data.get("api_base")) {
+                Some(Some(base)) => base.trim().to_owned(),
+                _ => String::from(spotify::API_BASE),
+            },
+            accounts_base: match map
+                .get("spotify")
+                .and_then(|data| data.get("accounts_base"))
+            {
+                Some(Some(base)) => base.trim().to_owned(),
+                _ => String::from(spotify::ACCOUNTS_BASE),
+            },
+            fixtures: match map
+                .get("spotify")
+                .map(|data| (data.get("record"), data.get("replay")))
+            {
+                Some((Some(Some(_)), Some(Some(_)))) => {
+                    return Err(String::from(
+                        "Can't record and replay fixtures at the same time!",
+                    ))
+                }
+                Some((Some(Some(record)), _)) => {
+                    Some(FixtureMode::Record(PathBuf::from(record.trim())))
+                }
+                Some((_, Some(Some(replay)))) => {
+                    Some(FixtureMode::Replay(PathBuf::from(replay.trim())))
+                }
+                _ => None,
+            },
+        },
+        logging: LoggingConfig {
+            level: match map.get("logging").and_then(|data| data.get("level")) {
+                Some(Some(level)) => level.trim().to_owned(),
+                _ => String::from("info"),
+            },
+            format: match map.get("logging").and_then(|data| data.get("format")) {
+                Some(Some(format)) if format.trim() == "json" => LogFormat::Json,
+                Some(Some(format)) if format.trim() == "pretty" => LogFormat::Pretty,
+                None | Some(None) => LogFormat::Pretty,
+                Some(Some(other)) => {
+                    return Err(format!(
+                        "Unknown log format {} (expected pretty or json)!",
+                        other
+                    ))
+                }
             },
-            None => panic!("No services specified!"),
         },
     };
 
+    // Under socket activation systemd decides where we listen, so the config can leave it out.
+    let activated = std::env::var_os("LISTEN_FDS").is_some();
+
+    if out.routing.is_empty() && out.unix.is_none() && !activated {
+        return Err(String::from(
+            "Nowhere to listen! Add http, https or unix to [routing].",
+        ));
+    }
+
+    if out.acme.is_some() && !out.routing.contains_key("http") && !activated {
+        return Err(String::from(
+            "ACME answers HTTP-01 challenges, so it needs routing.http!",
+        ));
+    }
+
+    if out.services.is_empty() {
+        return Err(String::from("No services specified!"));
+    }
+
     Ok(out)
 }
+
+/// Splits a comma- or space-separated config value.
+/// A key that has to be there, trimmed. `section` is only for saying where it's missing from.
+fn required(
+    data: &HashMap<String, Option<String>>,
+    key: &str,
+    section: &str,
+) -> Result<String, String> {
+    match data.get(key) {
+        Some(Some(value)) if !value.trim().is_empty() => Ok(value.trim().to_owned()),
+        _ => Err(format!("{} needs a {}!", section, key)),
+    }
+}
+
+fn list(value: Option<&Option<String>>) -> Option<Vec<String>> {
+    match value {
+        Some(Some(value)) => Some(
+            value
+                .split(|c: char| c == ',' || c.is_whitespace())
+                .filter(|item| !item.is_empty())
+                .map(str::to_owned)
+                .collect(),
+        ),
+        _ => None,
+    }
+}
+
+/// The app's client ID and secret, from the config if they're in it and a file if not.
+fn credentials(
+    data: Option<&HashMap<String, Option<String>>>,
+) -> Result<spotify::Credentials, String> {
+    let get = |key: &str| {
+        data.and_then(|data| data.get(key))
+            .and_then(|value| value.as_ref())
+            .map(|value| value.trim().to_owned())
+            .filter(|value| !value.is_empty())
+    };
+    match get("client_id") {
+        Some(username) => Ok(spotify::Credentials {
+            username,
+            password: get("client_secret"),
+        }),
+        None => spotify::read_creds_from_file(get("credentials").as_deref()),
+    }
+}
+
+/// Parses a routing entry into the addresses it names; there can be several, comma-separated.
+/// Takes anything SocketAddr does (bracketed IPv6 included), bare IPs, and hostnames,
+/// which get resolved here at startup. Missing ports default per scheme.
+fn parse_listen_addrs(value: &str, default_port: u16) -> Result<Vec<SocketAddr>, String> {
+    let mut addrs = Vec::new();
+    for entry in list(Some(&Some(value.to_owned()))).unwrap_or_default() {
+        if let Ok(addr) = SocketAddr::from_str(&entry) {
+            addrs.push(addr);
+            continue;
+        }
+        if let Ok(ip) = IpAddr::from_str(entry.trim_start_matches('[').trim_end_matches(']')) {
+            addrs.push(SocketAddr::new(ip, default_port));
+            continue;
+        }
+        let resolved = match entry.rsplit_once(':') {
+            Some((host, port)) => match u16::from_str(port) {
+                Ok(port) => (host, port).to_socket_addrs(),
+                Err(_) => return Err(format!("Invalid port in {}!", entry)),
+            },
+            None => (entry.as_str(), default_port).to_socket_addrs(),
+        }
+        .map_err(|e| format!("Failed to resolve {}: {}", entry, e))?;
+        addrs.extend(resolved);
+    }
+    if addrs.is_empty() {
+        return Err(format!("No addresses in routing entry {}!", value));
+    }
+    Ok(addrs)
+}
+
+/// Builds a service out of its config section.
+/// Scopes can be listed explicitly; otherwise we guess them from the endpoint.
+/// Services default to the logged-in user's token unless they ask for `auth: app`.
+fn parse_service(name: &str, svc: &HashMap<String, Option<String>>) -> Result<Service, String> {
+    let section = format!("Service {}", name);
+    let endpoint = spotify::with_additional_types(required(svc, "endpoint", &section)?);
+    let auth = match svc.get("auth") {
+        Some(Some(auth)) if auth.trim() == "app" => ServiceAuth::App,
+        Some(Some(auth)) if auth.trim() == "user" => ServiceAuth::User,
+        None => ServiceAuth::User,
+        Some(other) => return Err(format!("Unknown auth {:?} for service {}!", other, name)),
+    };
+    let cors = parse_cors(name, svc)?;
+    let query = parse_query(name, &endpoint, svc)?;
+    let domain = required(svc, "domain", &section)?;
+    let path = parse_path(name, &domain, &endpoint, svc)?;
+    let write = parse_write(name, &endpoint, svc)?;
+    if write.is_some() && auth == ServiceAuth::App {
+        return Err(format!(
+            "Service {} can't write with an app token; there's no account behind it!",
+            name
+        ));
+    }
+    let paginate = match svc.get("paginate") {
+        Some(Some(paginate)) => bool::from_str(paginate.trim())
+            .map_err(|_| format!("paginate for service {} should be true or false!", name))?,
+        _ => false,
+    };
+    let max_items = match svc.get("max_items") {
+        Some(Some(max_items)) => usize::from_str(max_items.trim())
+            .ok()
+            .filter(|max_items| *max_items > 0)
+            .ok_or_else(|| format!("max_items for service {} should be a count!", name))?,
+        _ => DEFAULT_MAX_ITEMS,
+    };
+    Ok(Service {
+        name: name.to_owned(),
+        domain,
+        target: required(svc, "target", &section)?,
+        scopes: match svc.get("scopes") {
+            // Client-credentials tokens don't carry any user scopes.
+            _ if auth == ServiceAuth::App => Vec::new(),
+            Some(Some(_)) => list(svc.get("scopes")).unwrap_or_default(),
+            _ if write.is_some() => spotify::write_endpoint(&endpoint)
+                .map_or(&[][..], |(_, scopes)| scopes)
+                .iter()
+                .map(|scope| scope.to_string())
+                .collect(),
+            _ => spotify::scopes_for_endpoint(&endpoint)
+                .iter()
+                .map(|scope| scope.to_string())
+                .collect(),
+        },
+        endpoint,
+        auth,
+        cors,
+        query,
+        path,
+        paginate: paginate.then_some(max_items),
+        // Writes don't have anything to extract.
+        extract: match (svc.get("extract"), &write) {
+            (Some(Some(extract)), _) => extract.to_owned(),
+            (_, Some(_)) => String::new(),
+            _ => return Err(format!("{} needs an extract!", section)),
+        },
+        write,
+    })
+}
+
+/// Reads a write service's keys. Only the endpoints spotify::write_endpoint knows about will do,
+/// so turning on POST can't point a service at anything else.
+fn parse_write(
+    name: &str,
+    endpoint: &str,
+    svc: &HashMap<String, Option<String>>,
+) -> Result<Option<WriteAction>, String> {
+    match svc.get("method") {
+        Some(Some(method)) if method.trim().eq_ignore_ascii_case("post") => (),
+        Some(Some(method)) if method.trim().eq_ignore_ascii_case("get") => return Ok(None),
+        None => return Ok(None),
+        Some(other) => return Err(format!("Unknown method {:?} for service {}!", other, name)),
+    }
+    let (method, _) = spotify::write_endpoint(endpoint).ok_or_else(|| {
+        format!(
+            "Service {} can't write to {}; only {} are allowed!",
+            name,
+            endpoint,
+            spotify::WRITE_ENDPOINTS
+                .iter()
+                .map(|(endpoint, _, _)| *endpoint)
+                .collect::<Vec<_>>()
+                .join(", ")
+        )
+    })?;
+    Ok(Some(WriteAction {
+        method,
+        rate_limit: match svc.get("rate_limit") {
+            Some(Some(rate_limit)) => RateLimit::from_str(rate_limit.trim())
+                .map_err(|e| format!("{} in service {}", e, name))?,
+            _ => DEFAULT_RATE_LIMIT,
+        },
+        secret: svc
+            .get("secret")
+            .and_then(|secret| secret.as_ref())
+            .map(|secret| secret.trim().to_owned())
+            .filter(|secret| !secret.is_empty()),
+        captcha: match (svc.get("captcha_verify"), svc.get("captcha_secret")) {
+            (Some(Some(verify_url)), Some(Some(secret))) => Some(Captcha {
+                verify_url: verify_url.trim().to_owned(),
+                secret: secret.trim().to_owned(),
+            }),
+            (None, None) => None,
+            _ => {
+                return Err(format!(
+                    "Service {} needs both captcha_verify and captcha_secret, or neither!",
+                    name
+                ))
+            }
+        },
+    }))
+}
+
+/// Reads a service's query.<name> keys. Nothing declared means nothing gets through, same as before.
+fn parse_query(
+    name: &str,
+    endpoint: &str,
+    svc: &HashMap<String, Option<String>>,
+) -> Result<Vec<QueryParam>, String> {
+    let baked: Vec<&str> = endpoint
+        .split_once('?')
+        .map(|(_, query)| {
+            query
+                .split('&')
+                .map(|pair| pair.split('=').next().unwrap_or_default())
+                .collect()
+        })
+        .unwrap_or_default();
+    let mut query: Vec<QueryParam> = svc
+        .iter()
+        .filter_map(|(key, spec)| Some((key.strip_prefix("query.")?, spec)))
+        .map(|(param, spec)| {
+            if baked.contains(&param) {
+                return Err(format!(
+                    "Service {} already has {} in its endpoint, so clients can't set it too!",
+                    name, param
+                ));
+            }
+            QueryParam::parse(param, spec.as_deref().unwrap_or_default())
+                .map_err(|e| format!("{} in service {}", e, name))
+        })
+        .collect::<Result<_, _>>()?;
+    query.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(query)
+}
+
+/// Reads the path.<name> rules for a service's `:name`s, making sure the domain and endpoint agree
+/// on what they are, and that there's no way in without a rule.
+fn parse_path(
+    name: &str,
+    domain: &str,
+    endpoint: &str,
+    svc: &HashMap<String, Option<String>>,
+) -> Result<Vec<PathParam>, String> {
+    let in_domain = params::route_params(domain);
+    let in_endpoint = params::endpoint_params(endpoint);
+    if let Some(missing) = in_endpoint.iter().find(|param| !in_domain.contains(param)) {
+        return Err(format!(
+            "Service {}'s endpoint uses {{{}}}, but its domain has no :{}!",
+            name, missing, missing
+        ));
+    }
+    if let Some(key) = svc
+        .keys()
+        .filter_map(|key| key.strip_prefix("path."))
+        .find(|param| !in_domain.contains(param))
+    {
+        return Err(format!(
+            "Service {} has a rule for {}, but no :{} in its domain!",
+            name, key, key
+        ));
+    }
+    in_domain
+        .iter()
+        .map(|param| {
+            if !in_endpoint.contains(param) {
+                return Err(format!(
+                    "Service {} takes :{} but its endpoint never uses it!",
+                    name, param
+                ));
+            }
+            let rule = match svc.get(&format!("path.{}", param)) {
+                Some(Some(rule)) => ParamRule::from_str(rule.trim())
+                    .map_err(|e| format!("{} for path.{} in service {}", e, param, name))?,
+                _ => {
+                    return Err(format!(
+                        "Service {} needs a path.{} rule saying which values are allowed!",
+                        name, param
+                    ))
+                }
+            };
+            Ok(PathParam {
+                name: param.to_string(),
+                rule,
+            })
+        })
+        .collect()
+}
+
+/// Reads a service's cors_* keys. Without cors_origins anyone can read it, same as before.
+fn parse_cors(name: &str, svc: &HashMap<String, Option<String>>) -> Result<CorsPolicy, String> {
+    let mut cors = CorsPolicy::default();
+    if let Some(origins) = list(svc.get("cors_origins")) {
+        cors.origins = origins
+            .iter()
+            .map(|origin| AllowedOrigin::from_str(origin))
+            .collect::<Result<_, _>>()
+            .map_err(|e| format!("{} in service {}", e, name))?;
+    }
+    cors.credentials = match svc.get("cors_credentials") {
+        Some(Some(credentials)) => bool::from_str(credentials.trim()).map_err(|_| {
+            format!(
+                "cors_credentials for service {} should be true or false!",
+                name
+            )
+        })?,
+        _ => false,
+    };
+    if cors.credentials && cors.origins.contains(&AllowedOrigin::Any) {
+        return Err(format!(
+            "Service {} can't allow credentials from every origin; list them in cors_origins!",
+            name
+        ));
+    }
+    cors.headers = list(svc.get("cors_headers")).unwrap_or_default();
+    cors.max_age = match svc.get("cors_max_age") {
+        Some(Some(max_age)) => Some(
+            u64::from_str(max_age.trim())
+                .map_err(|_| format!("cors_max_age for service {} should be seconds!", name))?,
+        ),
+        _ => None,
+    };
+    Ok(cors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addrs(value: &str, default_port: u16) -> Vec<String> {
+        parse_listen_addrs(value, default_port)
+            .unwrap()
+            .iter()
+            .map(SocketAddr::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn listen_addrs_take_bracketed_ipv6() {
+        assert_eq!(addrs("[::1]:8443", 443), ["[::1]:8443"]);
+        assert_eq!(addrs("[::]", 443), ["[::]:443"]);
+        assert_eq!(addrs("::1", 80), ["[::1]:80"]);
+    }
+
+    #[test]
+    fn listen_addrs_default_the_port() {
+        assert_eq!(addrs("127.0.0.1", 80), ["127.0.0.1:80"]);
+        assert_eq!(addrs("127.0.0.1:8080", 80), ["127.0.0.1:8080"]);
+        assert!(addrs("localhost", 443)
+            .iter()
+            .all(|addr| addr.ends_with(":443")));
+    }
+
+    #[test]
+    fn listen_addrs_take_lists() {
+        assert_eq!(addrs("0.0.0.0:80, [::]:80", 80), ["0.0.0.0:80", "[::]:80"]);
+        assert_eq!(
+            addrs("127.0.0.1,[::1] 10.0.0.1:81", 8000),
+            ["127.0.0.1:8000", "[::1]:8000", "10.0.0.1:81"]
+        );
+    }
+
+    #[test]
+    fn listen_addrs_turn_down_nonsense() {
+        assert!(parse_listen_addrs("", 80).is_err());
+        assert!(parse_listen_addrs(" , ", 80).is_err());
+        assert!(parse_listen_addrs("127.0.0.1:http", 80).is_err());
+        assert!(parse_listen_addrs("[::1]:99999", 80).is_err());
+    }
+}
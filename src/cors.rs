@@ -0,0 +1,299 @@
+use axum::{
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+
+use std::str::FromStr;
+
+use crate::app::ITEM_TYPE;
+
+/// One entry in a service's `cors_origins`: `*`, an exact origin like `https://example.com`,
+/// or every subdomain of one, like `https://*.example.com`. Leaving the scheme off matches either.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AllowedOrigin {
+    Any,
+    Exact(String),
+    Subdomains {
+        scheme: Option<String>,
+        /// The part after the `*`, dot included.
+        suffix: String,
+    },
+}
+
+impl AllowedOrigin {
+    fn matches(&self, origin: &str) -> bool {
+        match self {
+            AllowedOrigin::Any => true,
+            AllowedOrigin::Exact(exact) => exact.eq_ignore_ascii_case(origin),
+            AllowedOrigin::Subdomains { scheme, suffix } => {
+                let origin = origin.to_ascii_lowercase();
+                let host = match (scheme, origin.split_once("://")) {
+                    (Some(scheme), Some((theirs, host))) if *scheme == theirs => host,
+                    (None, Some((_, host))) => host,
+                    _ => return false,
+                };
+                // `*.example.com` is for the subdomains, not example.com itself.
+                host.len() > suffix.len() && host.ends_with(suffix.as_str())
+            }
+        }
+    }
+}
+
+impl FromStr for AllowedOrigin {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "*" {
+            return Ok(AllowedOrigin::Any);
+        }
+        let (scheme, host) = match s.split_once("://") {
+            Some((scheme, host)) => (Some(scheme.to_ascii_lowercase()), host),
+            None => (None, s),
+        };
+        if host.is_empty() || host.contains('/') {
+            return Err(format!(
+                "Invalid CORS origin {} (no paths or trailing slashes)!",
+                s
+            ));
+        }
+        match host.strip_prefix('*') {
+            Some(suffix) if suffix.starts_with('.') && !suffix.contains('*') => {
+                Ok(AllowedOrigin::Subdomains {
+                    scheme,
+                    suffix: suffix.to_ascii_lowercase(),
+                })
+            }
+            None if scheme.is_some() && !host.contains('*') => {
+                Ok(AllowedOrigin::Exact(s.to_owned()))
+            }
+            _ => Err(format!(
+                "Invalid CORS origin {} (expected *, scheme://host or [scheme://]*.domain)!",
+                s
+            )),
+        }
+    }
+}
+
+/// Who gets to read a service's responses from a browser, and on what terms.
+/// Preflights and the real responses are both answered from this, so they can't disagree.
+#[derive(Clone, Debug)]
+pub struct CorsPolicy {
+    pub origins: Vec<AllowedOrigin>,
+    pub credentials: bool,
+    /// Request headers a page may send; empty means just the CORS-safelisted ones.
+    pub headers: Vec<String>,
+    pub max_age: Option<u64>,
+}
+
+impl Default for CorsPolicy {
+    /// Anyone can read, nobody gets to send cookies along.
+    fn default() -> Self {
+        CorsPolicy {
+            origins: vec![AllowedOrigin::Any],
+            credentials: false,
+            headers: Vec::new(),
+            max_age: None,
+        }
+    }
+}
+
+impl CorsPolicy {
+    /// What to put in Access-Control-Allow-Origin for a request from `origin`, if anything.
+    /// A plain `*` only works without credentials; otherwise the origin gets echoed back.
+    fn allow_origin(&self, origin: Option<&HeaderValue>) -> Option<HeaderValue> {
+        if !self.credentials && self.origins.contains(&AllowedOrigin::Any) {
+            return Some(HeaderValue::from_static("*"));
+        }
+        let origin = origin?;
+        let allowed = origin
+            .to_str()
+            .is_ok_and(|origin| self.origins.iter().any(|o| o.matches(origin)));
+        allowed.then(|| origin.clone())
+    }
+
+    /// Whether the answer depends on who's asking, in which case caches need telling.
+    fn varies(&self) -> bool {
+        self.credentials || !self.origins.contains(&AllowedOrigin::Any)
+    }
+
+    /// The CORS headers for a normal response to this request.
+    pub fn headers(&self, request: &HeaderMap) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        if self.varies() {
+            headers.insert(header::VARY, HeaderValue::from_static("Origin"));
+        }
+        if let Some(allow) = self.allow_origin(request.get(header::ORIGIN)) {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, allow);
+            // Pages can't read anything past the basics unless we say so.
+            headers.insert(
+                header::ACCESS_CONTROL_EXPOSE_HEADERS,
+                HeaderValue::from_static(ITEM_TYPE),
+            );
+            if self.credentials {
+                headers.insert(
+                    header::ACCESS_CONTROL_ALLOW_CREDENTIALS,
+                    HeaderValue::from_static("true"),
+                );
+            }
+        }
+        headers
+    }
+
+    /// Answers an OPTIONS preflight. Origins we don't allow just get no CORS headers,
+    /// which the browser takes as a no. `methods` is whatever the route answers to, e.g. `GET, OPTIONS`.
+    pub fn preflight(&self, request: &HeaderMap, methods: &'static str) -> Response {
+        let mut headers = self.headers(request);
+        if headers.contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN) {
+            headers.insert(
+                header::ACCESS_CONTROL_ALLOW_METHODS,
+                HeaderValue::from_static(methods),
+            );
+            if !self.headers.is_empty() {
+                if let Ok(allowed) = HeaderValue::from_str(&self.headers.join(", ")) {
+                    headers.insert(header::ACCESS_CONTROL_ALLOW_HEADERS, allowed);
+                }
+            }
+            if let Some(max_age) = self.max_age {
+                headers.insert(header::ACCESS_CONTROL_MAX_AGE, HeaderValue::from(max_age));
+            }
+        }
+        (StatusCode::NO_CONTENT, headers).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(origins: &[&str], credentials: bool) -> CorsPolicy {
+        CorsPolicy {
+            origins: origins
+                .iter()
+                .map(|origin| AllowedOrigin::from_str(origin).unwrap())
+                .collect(),
+            credentials,
+            ..CorsPolicy::default()
+        }
+    }
+
+    fn from(origin: &str) -> HeaderMap {
+        let mut request = HeaderMap::new();
+        request.insert(header::ORIGIN, HeaderValue::from_str(origin).unwrap());
+        request
+    }
+
+    fn allowed(policy: &CorsPolicy, origin: &str) -> Option<HeaderValue> {
+        policy
+            .headers(&from(origin))
+            .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+            .cloned()
+    }
+
+    #[test]
+    fn exact_origins_match_whole() {
+        let exact = policy(&["https://example.com"], false);
+        assert_eq!(
+            allowed(&exact, "https://example.com").unwrap(),
+            "https://example.com"
+        );
+        assert_eq!(
+            allowed(&exact, "HTTPS://Example.com").unwrap(),
+            "HTTPS://Example.com"
+        );
+        for other in [
+            "http://example.com",
+            "https://example.com:8443",
+            "https://www.example.com",
+            "https://example.com.evil.net",
+        ] {
+            assert!(allowed(&exact, other).is_none(), "{}", other);
+        }
+    }
+
+    #[test]
+    fn wildcards_match_subdomains_only() {
+        let subdomains = policy(&["https://*.example.com"], false);
+        assert!(allowed(&subdomains, "https://www.example.com").is_some());
+        assert!(allowed(&subdomains, "https://a.b.example.com").is_some());
+        for other in [
+            "https://example.com",
+            "http://www.example.com",
+            "https://www.example.com.evil.net",
+            "https://evilexample.com",
+        ] {
+            assert!(allowed(&subdomains, other).is_none(), "{}", other);
+        }
+        let any_scheme = policy(&["*.example.com"], false);
+        assert!(allowed(&any_scheme, "http://www.example.com").is_some());
+        assert!(allowed(&any_scheme, "https://www.example.com").is_some());
+    }
+
+    #[test]
+    fn varies_by_origin_unless_anyone_can_read() {
+        let anyone = CorsPolicy::default();
+        let headers = anyone.headers(&from("https://example.com"));
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+        assert!(!headers.contains_key(header::VARY));
+
+        // Even a refusal varies, or a cache could hand it to an origin that's allowed.
+        let listed = policy(&["https://example.com"], false);
+        for origin in ["https://example.com", "https://elsewhere.com"] {
+            assert_eq!(listed.headers(&from(origin))[header::VARY], "Origin");
+        }
+        assert_eq!(listed.headers(&HeaderMap::new())[header::VARY], "Origin");
+    }
+
+    #[test]
+    fn credentials_echo_the_origin_instead_of_a_star() {
+        let credentials = policy(&["https://example.com"], true);
+        let headers = credentials.headers(&from("https://example.com"));
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://example.com"
+        );
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_CREDENTIALS], "true");
+
+        let refused = credentials.headers(&from("https://elsewhere.com"));
+        assert!(!refused.contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+        assert!(!refused.contains_key(header::ACCESS_CONTROL_ALLOW_CREDENTIALS));
+
+        // The config won't have `*` with credentials, but if it got here anyway it's never a literal `*`.
+        let star = policy(&["*"], true);
+        let headers = star.headers(&from("https://example.com"));
+        assert_ne!(headers[header::ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+        assert_eq!(headers[header::VARY], "Origin");
+    }
+
+    #[test]
+    fn preflights_turn_away_other_origins() {
+        let mut listed = policy(&["https://example.com"], false);
+        listed.headers = vec![String::from("X-Captcha-Token")];
+        listed.max_age = Some(600);
+
+        let ok = listed.preflight(&from("https://example.com"), "GET, OPTIONS");
+        assert_eq!(ok.status(), StatusCode::NO_CONTENT);
+        let headers = ok.headers();
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_METHODS],
+            "GET, OPTIONS"
+        );
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_HEADERS],
+            "X-Captcha-Token"
+        );
+        assert_eq!(headers[header::ACCESS_CONTROL_MAX_AGE], "600");
+
+        let refused = listed.preflight(&from("https://elsewhere.com"), "GET, OPTIONS");
+        assert_eq!(refused.status(), StatusCode::NO_CONTENT);
+        let headers = refused.headers();
+        assert_eq!(headers[header::VARY], "Origin");
+        for name in [
+            header::ACCESS_CONTROL_ALLOW_ORIGIN,
+            header::ACCESS_CONTROL_ALLOW_METHODS,
+            header::ACCESS_CONTROL_ALLOW_HEADERS,
+            header::ACCESS_CONTROL_MAX_AGE,
+        ] {
+            assert!(!headers.contains_key(&name), "{}", name);
+        }
+    }
+}
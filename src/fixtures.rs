@@ -0,0 +1,186 @@
+use axum::http;
+
+use reqwest::Response;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// What recording a call leaves behind: enough to hand the same response back later,
+/// with anything that looks like a token scrubbed out.
+#[derive(Serialize, Deserialize)]
+struct Fixture {
+    target: String,
+    endpoint: String,
+    status: u16,
+    headers: BTreeMap<String, String>,
+    /// JSON bodies are kept as JSON so they're easy to read and edit; anything else as a string.
+    body: Value,
+}
+
+const REDACTED: &str = "[redacted]";
+
+/// Response headers that either could carry something we shouldn't be writing to disk,
+/// or only made sense for the connection they came in on.
+const SKIPPED_HEADERS: &[&str] = &[
+    "set-cookie",
+    "authorization",
+    "www-authenticate",
+    "content-length",
+    "transfer-encoding",
+    "connection",
+];
+
+/// One file per target and endpoint, query string included, so replaying `me/top/tracks?limit=1`
+/// doesn't get you `me/top/tracks?limit=50`. Recording the same call again replaces it.
+fn fixture_path(dir: &Path, accounts: bool, endpoint: &str) -> PathBuf {
+    let name: String = endpoint
+        .trim_matches('/')
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '.' => c,
+            _ => '_',
+        })
+        .collect();
+    dir.join(if accounts { "accounts" } else { "api" })
+        .join(format!("{}.json", name))
+}
+
+/// Writes the response down and hands back an identical one, since reading the body used up the original.
+/// Failing to write the fixture is logged, not fatal; the caller still gets its response.
+pub async fn record(dir: &Path, accounts: bool, endpoint: &str, response: Response) -> Response {
+    let status = response.status();
+    let headers = response.headers().clone();
+    let bytes = match response.bytes().await {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            tracing::warn!(endpoint, "Failed to read the response to record it: {}", e);
+            return rebuild(status, &headers, Vec::new());
+        }
+    };
+
+    let body = match serde_json::from_slice::<Value>(&bytes) {
+        Ok(mut json) => {
+            redact(&mut json);
+            json
+        }
+        Err(_) => Value::String(String::from_utf8_lossy(&bytes).into_owned()),
+    };
+    let fixture = Fixture {
+        target: String::from(if accounts { "accounts" } else { "api" }),
+        endpoint: endpoint.to_owned(),
+        status: status.as_u16(),
+        headers: headers
+            .iter()
+            .filter(|(name, _)| !SKIPPED_HEADERS.contains(&name.as_str()))
+            .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_owned())))
+            .collect(),
+        body,
+    };
+    let path = fixture_path(dir, accounts, endpoint);
+    let written = path
+        .parent()
+        .map_or(Ok(()), std::fs::create_dir_all)
+        .and_then(|_| {
+            std::fs::write(
+                &path,
+                serde_json::to_vec_pretty(&fixture).map_err(std::io::Error::other)?,
+            )
+        });
+    match written {
+        Ok(()) => tracing::debug!(path = %path.display(), "Recorded fixture"),
+        Err(e) => tracing::warn!(path = %path.display(), "Failed to write fixture: {}", e),
+    }
+
+    rebuild(status, &headers, bytes.to_vec())
+}
+
+/// Serves a call from its fixture. Anything we never recorded is a 404, so it's obvious what's missing.
+pub fn replay(dir: &Path, accounts: bool, endpoint: &str) -> Response {
+    let path = fixture_path(dir, accounts, endpoint);
+    let fixture = match std::fs::read(&path)
+        .map_err(|e| e.to_string())
+        .and_then(|bytes| serde_json::from_slice::<Fixture>(&bytes).map_err(|e| e.to_string()))
+    {
+        Ok(fixture) => fixture,
+        Err(e) => {
+            tracing::warn!(path = %path.display(), "No fixture to replay: {}", e);
+            return rebuild(
+                http::StatusCode::NOT_FOUND,
+                &http::HeaderMap::new(),
+                format!("No fixture at {}", path.display()).into_bytes(),
+            );
+        }
+    };
+
+    let mut headers = http::HeaderMap::new();
+    for (name, value) in fixture.headers.iter() {
+        if let (Ok(name), Ok(value)) = (
+            http::HeaderName::from_bytes(name.as_bytes()),
+            http::HeaderValue::from_str(value),
+        ) {
+            headers.insert(name, value);
+        }
+    }
+    let body = match fixture.body {
+        Value::String(text) => text.into_bytes(),
+        Value::Null => Vec::new(),
+        json => json.to_string().into_bytes(),
+    };
+    rebuild(
+        http::StatusCode::from_u16(fixture.status).unwrap_or(http::StatusCode::OK),
+        &headers,
+        body,
+    )
+}
+
+/// Stands in for the token endpoint while replaying, so nothing has to go upstream at all.
+pub fn token_response() -> Response {
+    let mut headers = http::HeaderMap::new();
+    headers.insert(
+        http::header::CONTENT_TYPE,
+        http::HeaderValue::from_static("application/json"),
+    );
+    rebuild(
+        http::StatusCode::OK,
+        &headers,
+        json!({
+            "access_token": "replay",
+            "refresh_token": "replay",
+            "token_type": "Bearer",
+            "expires_in": 3600,
+        })
+        .to_string()
+        .into_bytes(),
+    )
+}
+
+/// Blanks out the value of any key that sounds like a token, however deep it is.
+fn redact(json: &mut Value) {
+    match json {
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                if key.to_ascii_lowercase().contains("token") && value.is_string() {
+                    *value = Value::String(String::from(REDACTED));
+                } else {
+                    redact(value);
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(redact),
+        _ => (),
+    }
+}
+
+pub(crate) fn rebuild(
+    status: http::StatusCode,
+    headers: &http::HeaderMap,
+    body: Vec<u8>,
+) -> Response {
+    let mut response = http::Response::new(body);
+    *response.status_mut() = status;
+    *response.headers_mut() = headers.clone();
+    Response::from(response)
+}
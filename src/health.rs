@@ -0,0 +1,53 @@
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Json, Response},
+};
+
+use serde_json::{json, Map, Value};
+
+use std::time::SystemTime;
+
+use crate::authstate::AuthState;
+
+/// Just says we're up. Anything more and a load balancer would start killing us for Spotify's problems.
+pub async fn healthz() -> &'static str {
+    "ok"
+}
+
+/// Whether the services can actually answer, per token they use: there's a token, it hasn't
+/// run out, the last refresh didn't fail, and Spotify didn't turn down the last call as unauthorized.
+/// 503s when any of them can't, so "running but nobody's logged in" doesn't look like "working".
+pub fn readyz(tokens: &[(&str, &AuthState)]) -> Response {
+    let mut ready = true;
+    let mut report = Map::new();
+    for (name, auth) in tokens {
+        let current = auth.tokens();
+        let expires_in = current.as_ref().map(|token_set| {
+            match token_set.expires_at.duration_since(SystemTime::now()) {
+                Ok(left) => left.as_secs() as i64,
+                Err(e) => -(e.duration().as_secs() as i64),
+            }
+        });
+        let token_ready = current.as_ref().is_some_and(|t| !t.is_expired())
+            && auth.last_refresh() != Some(false)
+            && auth.last_upstream() != Some(StatusCode::UNAUTHORIZED.as_u16());
+        ready &= token_ready;
+        report.insert(
+            name.to_string(),
+            json!({
+                "ready": token_ready,
+                "has_tokens": current.is_some(),
+                "expires_in": expires_in,
+                "last_refresh_succeeded": auth.last_refresh(),
+                "last_upstream_status": auth.last_upstream(),
+            }),
+        );
+    }
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    report.insert(String::from("ready"), Value::Bool(ready));
+    (status, Json(Value::Object(report))).into_response()
+}
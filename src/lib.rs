@@ -0,0 +1,45 @@
+//! The Spotify auth and proxying behind obscurify, for serving from your own axum app.
+//! `router(config)` gets you every route the binary serves; hang on to a `SpotifyClient`
+//! and use `router_with_client` if you want at the tokens yourself. `run` is the whole binary.
+
+mod acme;
+mod actions;
+mod app;
+pub mod audit;
+pub mod authstate;
+mod cli;
+pub mod conf;
+pub mod cors;
+mod fixtures;
+pub mod health;
+pub mod logging;
+pub mod metrics;
+pub mod models;
+pub mod params;
+pub mod proxy;
+mod serve;
+pub mod spotify;
+mod store;
+mod systemd;
+
+pub use app::{router, router_with_client};
+pub use conf::Config;
+pub use spotify::SpotifyClient;
+
+use conf::Command;
+
+use std::sync::Arc;
+
+/// Does what the binary does for `command`: serves until it's told to stop,
+/// or authorizes/logs out from the terminal.
+pub async fn run(command: Command, config: Config) -> Result<(), String> {
+    match command {
+        Command::Serve => {
+            let spotify = Arc::new(SpotifyClient::new(Arc::new(config)));
+            spotify.start();
+            serve::run(spotify).await
+        }
+        Command::Auth => cli::auth(&config).await,
+        Command::Logout => cli::logout(&config).await,
+    }
+}
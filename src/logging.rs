@@ -0,0 +1,52 @@
+use axum::{extract::Request, middleware::Next, response::Response};
+
+use std::time::Instant;
+
+use tracing::Instrument;
+use tracing_subscriber::EnvFilter;
+
+use crate::conf::{LogFormat, LoggingConfig};
+use crate::proxy::ClientInfo;
+
+/// Sets up the global subscriber from [logging]. RUST_LOG wins over the configured level if it's set.
+/// Everything goes to stderr, same as it always has.
+pub fn init(config: &LoggingConfig) {
+    let filter = EnvFilter::try_from_default_env()
+        .or_else(|_| EnvFilter::try_new(&config.level))
+        .unwrap_or_else(|e| {
+            eprintln!("Invalid log level {}, using info: {}", config.level, e);
+            EnvFilter::new("info")
+        });
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr);
+    match config.format {
+        LogFormat::Pretty => builder.init(),
+        LogFormat::Json => builder.json().init(),
+    }
+}
+
+/// Puts every request in its own span and writes one access log line when it's done.
+/// Only the path gets logged: the query string is where auth codes and states turn up,
+/// and headers are where tokens and the admin secret live.
+pub async fn access_log(request: Request, next: Next) -> Response {
+    let client = request.extensions().get::<ClientInfo>().copied();
+    let span = tracing::info_span!(
+        "request",
+        method = %request.method(),
+        path = %request.uri().path(),
+        client = client.and_then(|c| c.ip).map(tracing::field::display),
+        https = client.is_some_and(|c| c.https),
+    );
+    let started = Instant::now();
+    let response = next.run(request).instrument(span.clone()).await;
+    span.in_scope(|| {
+        tracing::info!(
+            target: "access",
+            status = response.status().as_u16(),
+            latency_ms = started.elapsed().as_millis() as u64,
+            "served"
+        )
+    });
+    response
+}
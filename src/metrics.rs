@@ -0,0 +1,137 @@
+use axum::{
+    extract::{MatchedPath, Request},
+    http::header,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use lazy_static::lazy_static;
+
+use prometheus::{
+    Encoder, GaugeVec, HistogramOpts, HistogramVec, IntCounterVec, Opts, Registry, TextEncoder,
+};
+
+use std::time::{Instant, SystemTime};
+
+use crate::authstate::AuthState;
+
+lazy_static! {
+    static ref REGISTRY: Registry = Registry::new();
+    static ref REQUESTS: IntCounterVec = register(IntCounterVec::new(
+        Opts::new(
+            "obscurify_requests_total",
+            "Requests served, by route and status."
+        ),
+        &["route", "status"],
+    ));
+    static ref LATENCY: HistogramVec = register(HistogramVec::new(
+        HistogramOpts::new(
+            "obscurify_request_duration_seconds",
+            "How long requests took to answer, by route.",
+        ),
+        &["route"],
+    ));
+    static ref UPSTREAM: IntCounterVec = register(IntCounterVec::new(
+        Opts::new(
+            "obscurify_upstream_responses_total",
+            "Responses from the Spotify API, by status code.",
+        ),
+        &["status"],
+    ));
+    static ref REFRESHES: IntCounterVec = register(IntCounterVec::new(
+        Opts::new(
+            "obscurify_token_refreshes_total",
+            "Token refreshes, by token (user or app) and outcome.",
+        ),
+        &["token", "outcome"],
+    ));
+    static ref EXPIRY: GaugeVec = register(GaugeVec::new(
+        Opts::new(
+            "obscurify_token_expiry_seconds",
+            "Seconds until the current token runs out (negative once it has). Absent without one.",
+        ),
+        &["token"],
+    ));
+}
+
+fn register<M: prometheus::core::Collector + Clone + 'static>(metric: prometheus::Result<M>) -> M {
+    let metric = metric.expect("Metric definitions should be valid!");
+    REGISTRY
+        .register(Box::new(metric.clone()))
+        .expect("Metrics should only be registered once!");
+    metric
+}
+
+/// Which token a refresh or expiry is about.
+#[derive(Clone, Copy)]
+pub enum TokenKind {
+    User,
+    App,
+}
+
+impl TokenKind {
+    fn label(self) -> &'static str {
+        match self {
+            TokenKind::User => "user",
+            TokenKind::App => "app",
+        }
+    }
+}
+
+/// Counts and times every request against the route it matched.
+/// Goes on as a route_layer, so 404s from scanners can't blow up the label set.
+pub async fn track(request: Request, next: Next) -> Response {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or("", |path| path.as_str())
+        .to_owned();
+    let started = Instant::now();
+    let response = next.run(request).await;
+    LATENCY
+        .with_label_values(&[&route])
+        .observe(started.elapsed().as_secs_f64());
+    REQUESTS
+        .with_label_values(&[&route, response.status().as_str()])
+        .inc();
+    response
+}
+
+pub fn upstream(status: reqwest::StatusCode) {
+    UPSTREAM.with_label_values(&[status.as_str()]).inc();
+}
+
+pub fn refreshed(token: TokenKind, succeeded: bool) {
+    let outcome = if succeeded { "success" } else { "failure" };
+    REFRESHES.with_label_values(&[token.label(), outcome]).inc();
+}
+
+/// Renders everything in the Prometheus text format. Token expiry is read off the AuthStates
+/// right now rather than kept up to date as it ticks down.
+pub fn render(tokens: &[(TokenKind, &AuthState)]) -> Response {
+    for (kind, auth) in tokens {
+        match auth.tokens() {
+            Some(token_set) => {
+                let expiry = match token_set.expires_at.duration_since(SystemTime::now()) {
+                    Ok(left) => left.as_secs_f64(),
+                    Err(e) => -e.duration().as_secs_f64(),
+                };
+                EXPIRY.with_label_values(&[kind.label()]).set(expiry);
+            }
+            None => {
+                let _ = EXPIRY.remove_label_values(&[kind.label()]);
+            }
+        }
+    }
+    let encoder = TextEncoder::new();
+    let mut body = Vec::new();
+    if let Err(e) = encoder.encode(&REGISTRY.gather(), &mut body) {
+        tracing::error!("Failed to encode metrics: {}", e);
+        return axum::http::StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+    (
+        [(header::CONTENT_TYPE, encoder.format_type().to_owned())],
+        body,
+    )
+        .into_response()
+}
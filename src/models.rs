@@ -0,0 +1,299 @@
+//! Just enough of Spotify's response shapes to stop picking through JSON by hand.
+//! Anything Spotify marks as nullable (or leaves out for local files and the like) is an Option,
+//! and fields we don't care about are skipped, so new ones on Spotify's end don't break anything.
+//! https://developer.spotify.com/documentation/web-api/reference
+
+use serde::{Deserialize, Serialize};
+
+/// GET me/player/currently-playing. A 204 (nothing playing) never gets this far.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CurrentlyPlaying {
+    pub is_playing: bool,
+    pub progress_ms: Option<u64>,
+    pub timestamp: Option<u64>,
+    pub currently_playing_type: PlayingType,
+    pub context: Option<Context>,
+    /// Null for ads, and for episodes unless they were asked for with `additional_types=episode`
+    /// (which SpotifyClient always does).
+    pub item: Option<PlayableItem>,
+}
+
+/// What's coming out of the speakers. Ads don't get an item at all.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PlayingType {
+    Track,
+    Episode,
+    Ad,
+    #[serde(other)]
+    Unknown,
+}
+
+/// GET me/player: the same as currently-playing, plus which device and what mode it's in.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlaybackState {
+    pub device: Option<Device>,
+    pub repeat_state: Option<String>,
+    pub shuffle_state: Option<bool>,
+    #[serde(flatten)]
+    pub playing: CurrentlyPlaying,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Device {
+    pub id: Option<String>,
+    pub name: String,
+    #[serde(rename = "type")]
+    pub device_type: String,
+    #[serde(default)]
+    pub is_active: bool,
+    #[serde(default)]
+    pub is_private_session: bool,
+    pub volume_percent: Option<u8>,
+}
+
+/// Anything that can sit in the player or a playlist.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum PlayableItem {
+    Track(Track),
+    Episode(Episode),
+}
+
+impl PlayableItem {
+    /// Local files don't have one.
+    pub fn id(&self) -> Option<&str> {
+        match self {
+            PlayableItem::Track(track) => track.id.as_deref(),
+            PlayableItem::Episode(episode) => episode.id.as_deref(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            PlayableItem::Track(track) => &track.name,
+            PlayableItem::Episode(episode) => &episode.name,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Track {
+    pub id: Option<String>,
+    pub name: String,
+    pub uri: Option<String>,
+    pub duration_ms: u64,
+    #[serde(default)]
+    pub explicit: bool,
+    #[serde(default)]
+    pub is_local: bool,
+    pub popularity: Option<u32>,
+    pub preview_url: Option<String>,
+    pub album: Option<SimplifiedAlbum>,
+    #[serde(default)]
+    pub artists: Vec<SimplifiedArtist>,
+    #[serde(default)]
+    pub external_urls: ExternalUrls,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Episode {
+    pub id: Option<String>,
+    pub name: String,
+    pub uri: Option<String>,
+    pub duration_ms: u64,
+    #[serde(default)]
+    pub explicit: bool,
+    pub description: Option<String>,
+    pub release_date: Option<String>,
+    #[serde(default)]
+    pub images: Vec<Image>,
+    /// Left out when the episode comes back as part of its own show.
+    pub show: Option<SimplifiedShow>,
+    #[serde(default)]
+    pub external_urls: ExternalUrls,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SimplifiedAlbum {
+    pub id: Option<String>,
+    pub name: String,
+    pub uri: Option<String>,
+    pub album_type: Option<String>,
+    pub release_date: Option<String>,
+    #[serde(default)]
+    pub images: Vec<Image>,
+    #[serde(default)]
+    pub artists: Vec<SimplifiedArtist>,
+    #[serde(default)]
+    pub external_urls: ExternalUrls,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SimplifiedArtist {
+    pub id: Option<String>,
+    pub name: String,
+    pub uri: Option<String>,
+    #[serde(default)]
+    pub external_urls: ExternalUrls,
+}
+
+/// What top artists come back as.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Artist {
+    pub id: String,
+    pub name: String,
+    pub uri: Option<String>,
+    #[serde(default)]
+    pub genres: Vec<String>,
+    pub popularity: Option<u32>,
+    #[serde(default)]
+    pub images: Vec<Image>,
+    #[serde(default)]
+    pub external_urls: ExternalUrls,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SimplifiedShow {
+    pub id: String,
+    pub name: String,
+    pub publisher: Option<String>,
+    pub uri: Option<String>,
+    #[serde(default)]
+    pub images: Vec<Image>,
+    #[serde(default)]
+    pub external_urls: ExternalUrls,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Image {
+    pub url: String,
+    pub height: Option<u32>,
+    pub width: Option<u32>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ExternalUrls {
+    pub spotify: Option<String>,
+}
+
+/// The album, playlist, artist or show something's being played from.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Context {
+    #[serde(rename = "type")]
+    pub context_type: String,
+    pub uri: String,
+    #[serde(default)]
+    pub external_urls: ExternalUrls,
+}
+
+/// One page of a list endpoint. `next` is a full URL, or None on the last page.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub limit: Option<u32>,
+    pub offset: Option<u32>,
+    pub total: Option<u32>,
+    pub next: Option<String>,
+    pub previous: Option<String>,
+}
+
+/// Recently-played pages by timestamp rather than offset, so it has cursors instead.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CursorPage<T> {
+    pub items: Vec<T>,
+    pub limit: Option<u32>,
+    pub next: Option<String>,
+    pub cursors: Option<Cursors>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Cursors {
+    pub after: Option<String>,
+    pub before: Option<String>,
+}
+
+/// GET me/player/recently-played. Only ever tracks.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlayHistory {
+    pub track: Track,
+    pub played_at: String,
+    pub context: Option<Context>,
+}
+
+/// How far back me/top looks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TimeRange {
+    /// About four weeks.
+    ShortTerm,
+    /// About six months. Spotify's default.
+    MediumTerm,
+    /// About a year.
+    LongTerm,
+}
+
+impl TimeRange {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TimeRange::ShortTerm => "short_term",
+            TimeRange::MediumTerm => "medium_term",
+            TimeRange::LongTerm => "long_term",
+        }
+    }
+}
+
+/// What me/playlists lists: everything but the tracks themselves.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SimplifiedPlaylist {
+    pub id: String,
+    pub name: String,
+    pub description: Option<String>,
+    pub public: Option<bool>,
+    #[serde(default)]
+    pub collaborative: bool,
+    pub uri: Option<String>,
+    /// Spotify sends null here for playlists without a cover.
+    pub images: Option<Vec<Image>>,
+    pub owner: PlaylistOwner,
+    pub tracks: Option<TracksRef>,
+    #[serde(default)]
+    pub external_urls: ExternalUrls,
+}
+
+/// GET playlists/{id}: the playlist along with its first page of tracks.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Playlist {
+    pub id: String,
+    pub name: String,
+    pub description: Option<String>,
+    pub public: Option<bool>,
+    #[serde(default)]
+    pub collaborative: bool,
+    pub uri: Option<String>,
+    pub images: Option<Vec<Image>>,
+    pub owner: PlaylistOwner,
+    pub tracks: Page<PlaylistItem>,
+    #[serde(default)]
+    pub external_urls: ExternalUrls,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlaylistOwner {
+    pub id: String,
+    pub display_name: Option<String>,
+}
+
+/// Where to find a playlist's tracks, and how many there are.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TracksRef {
+    pub href: Option<String>,
+    pub total: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlaylistItem {
+    pub added_at: Option<String>,
+    /// Null when the track's been pulled from Spotify.
+    pub track: Option<PlayableItem>,
+}
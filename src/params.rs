@@ -0,0 +1,178 @@
+use regex::Regex;
+
+use std::collections::HashMap;
+use std::str::FromStr;
+
+/// What a client-supplied value has to look like before it goes anywhere near Spotify.
+#[derive(Clone, Debug)]
+pub enum ParamRule {
+    /// `short_term|medium_term|long_term`: exactly one of these.
+    OneOf(Vec<String>),
+    /// `1..50`: a whole number in here, ends included.
+    Range(i64, i64),
+    /// `re:[A-Za-z0-9]{22}`: the whole value has to match.
+    Pattern(Regex),
+}
+
+impl ParamRule {
+    /// Whatever the rule says, the value also has to be plain enough that it can't escape
+    /// from where it's put: no slashes, no `..`, nothing that needs escaping.
+    /// Colons are fine, so `spotify:track:...` URIs get through.
+    pub fn check(&self, value: &str) -> bool {
+        if !safe(value) {
+            return false;
+        }
+        match self {
+            ParamRule::OneOf(allowed) => allowed.iter().any(|a| a == value),
+            ParamRule::Range(min, max) => {
+                i64::from_str(value).is_ok_and(|n| (*min..=*max).contains(&n))
+            }
+            ParamRule::Pattern(pattern) => pattern.is_match(value),
+        }
+    }
+}
+
+fn safe(value: &str) -> bool {
+    !value.is_empty()
+        && value != "."
+        && value != ".."
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_.,:".contains(c))
+}
+
+impl FromStr for ParamRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(pattern) = s.strip_prefix("re:") {
+            // Anchored, so `[a-z]+` can't be satisfied by a value that merely contains a match.
+            return Regex::new(&format!("^(?:{})$", pattern.trim()))
+                .map(ParamRule::Pattern)
+                .map_err(|e| format!("Invalid pattern {}: {}", pattern, e));
+        }
+        if let Some((min, max)) = s.split_once("..") {
+            return match (i64::from_str(min.trim()), i64::from_str(max.trim())) {
+                (Ok(min), Ok(max)) if min <= max => Ok(ParamRule::Range(min, max)),
+                _ => Err(format!("Invalid range {} (expected min..max)!", s)),
+            };
+        }
+        let allowed: Vec<String> = s.split('|').map(|a| a.trim().to_owned()).collect();
+        match allowed.iter().all(|a| safe(a)) {
+            true => Ok(ParamRule::OneOf(allowed)),
+            false => Err(format!(
+                "Invalid choices {} (expected a|b|c, letters, digits and -_.,: only, or re:<pattern>)!",
+                s
+            )),
+        }
+    }
+}
+
+/// A query parameter a service lets through from the incoming request, from a `query.<name>` key:
+/// the rule, then optionally `= default` for when the client leaves it off.
+/// A `re:` pattern takes the rest of the line as is, `=`s and all, so it can't have a default.
+#[derive(Clone, Debug)]
+pub struct QueryParam {
+    pub name: String,
+    pub rule: ParamRule,
+    pub default: Option<String>,
+}
+
+impl QueryParam {
+    pub fn parse(name: &str, spec: &str) -> Result<QueryParam, String> {
+        let (rule, default) = match spec.split_once('=') {
+            Some((rule, default)) if !rule.trim_start().starts_with("re:") => {
+                (rule, Some(default.trim().to_owned()))
+            }
+            _ => (spec, None),
+        };
+        let param = QueryParam {
+            name: name.to_owned(),
+            rule: ParamRule::from_str(rule.trim())
+                .map_err(|e| format!("Query parameter {}: {}", name, e))?,
+            default,
+        };
+        match &param.default {
+            Some(default) if !param.rule.check(default) => Err(format!(
+                "Query parameter {} defaults to {}, which its own rule doesn't allow!",
+                name, default
+            )),
+            _ => Ok(param),
+        }
+    }
+}
+
+/// Picks the declared parameters out of the incoming query, checks them and fills in defaults.
+/// Anything undeclared is dropped on the floor; anything declared but wrong is an error for the client.
+pub fn forward(
+    params: &[QueryParam],
+    incoming: &HashMap<String, String>,
+) -> Result<Vec<(String, String)>, String> {
+    let mut forwarded = Vec::new();
+    for param in params {
+        match (incoming.get(&param.name), &param.default) {
+            (Some(value), _) if param.rule.check(value) => {
+                forwarded.push((param.name.clone(), value.clone()))
+            }
+            (Some(value), _) => {
+                return Err(format!("{} can't be {}", param.name, value));
+            }
+            (None, Some(default)) => forwarded.push((param.name.clone(), default.clone())),
+            (None, None) => (),
+        }
+    }
+    Ok(forwarded)
+}
+
+/// Why a request's parameters didn't make it to Spotify.
+#[derive(Debug)]
+pub enum Rejected {
+    /// A path parameter failed its rule: as far as the client's concerned, there's nothing there.
+    Path(String),
+    /// A query parameter failed its rule.
+    Query(String),
+}
+
+/// A `:name` in a service's domain, with the `path.<name>` rule its value has to pass
+/// before it gets put into the endpoint's `{name}`.
+#[derive(Clone, Debug)]
+pub struct PathParam {
+    pub name: String,
+    pub rule: ParamRule,
+}
+
+/// Fills the endpoint's `{name}`s in from the matched path. A value that fails its rule is
+/// reported by name, so the caller can treat it as something that doesn't exist.
+pub fn fill_path(
+    endpoint: &str,
+    params: &[PathParam],
+    incoming: &HashMap<String, String>,
+) -> Result<String, String> {
+    let mut filled = endpoint.to_owned();
+    for param in params {
+        match incoming.get(&param.name) {
+            Some(value) if param.rule.check(value) => {
+                filled = filled.replace(&format!("{{{}}}", param.name), value);
+            }
+            _ => return Err(format!("No such {}", param.name)),
+        }
+    }
+    Ok(filled)
+}
+
+/// The `:name`s in an axum route, in order.
+pub fn route_params(route: &str) -> Vec<&str> {
+    route
+        .split('/')
+        .filter_map(|segment| segment.strip_prefix(':'))
+        .collect()
+}
+
+/// The `{name}`s in an endpoint, in order.
+pub fn endpoint_params(endpoint: &str) -> Vec<&str> {
+    endpoint
+        .split('{')
+        .skip(1)
+        .filter_map(|rest| Some(rest.split_once('}')?.0))
+        .collect()
+}
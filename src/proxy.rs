@@ -0,0 +1,220 @@
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::HeaderMap,
+    middleware::Next,
+    response::Response,
+};
+
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+const X_FORWARDED_PROTO: &str = "x-forwarded-proto";
+
+/// A proxy we believe when it tells us who the client is: one address, or a CIDR block.
+#[derive(Clone, Copy, Debug)]
+pub struct TrustedProxy {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl TrustedProxy {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => prefix_eq(
+                u32::from(net) as u128,
+                u32::from(ip) as u128,
+                32,
+                self.prefix,
+            ),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_eq(u128::from(net), u128::from(ip), 128, self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_eq(a: u128, b: u128, bits: u8, prefix: u8) -> bool {
+    let shift = bits - prefix;
+    shift >= bits || (a >> shift) == (b >> shift)
+}
+
+impl FromStr for TrustedProxy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr = IpAddr::from_str(addr.trim_start_matches('[').trim_end_matches(']'))
+            .map_err(|e| format!("Invalid trusted proxy {}: {}", s, e))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => u8::from_str(prefix)
+                .ok()
+                .filter(|prefix| *prefix <= max)
+                .ok_or(format!("Invalid prefix length in trusted proxy {}!", s))?,
+            None => max,
+        };
+        Ok(TrustedProxy { addr, prefix })
+    }
+}
+
+/// Marks requests that came in over the Unix socket.
+/// Only a local reverse proxy can reach that, so it's trusted by definition.
+#[derive(Clone, Copy, Debug)]
+pub struct UnixPeer;
+
+/// Marks requests that came in on one of our own TLS listeners.
+#[derive(Clone, Copy, Debug)]
+pub struct ServedOverTls;
+
+/// Who we're actually talking to, once any trusted proxies in front of us are accounted for.
+/// `ip` is None when a Unix socket proxy didn't say.
+#[derive(Clone, Copy, Debug)]
+pub struct ClientInfo {
+    pub ip: Option<IpAddr>,
+    pub https: bool,
+}
+
+/// Works out the real client for every request and stashes it as a ClientInfo extension.
+/// X-Forwarded-For is read right to left, skipping our own proxies, so a client can't
+/// just claim to be someone else by sending the header themselves.
+/// Untrusted peers get their forwarding headers stripped entirely.
+pub async fn client_info(
+    State(trusted): State<Arc<Vec<TrustedProxy>>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let peer = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip());
+    let via_unix = request.extensions().get::<UnixPeer>().is_some();
+    let is_trusted = |ip: IpAddr| trusted.iter().any(|proxy| proxy.contains(ip));
+    let direct_https = request.extensions().get::<ServedOverTls>().is_some();
+
+    let info = if via_unix || peer.is_some_and(is_trusted) {
+        ClientInfo {
+            ip: forwarded_client(&forwarded_for(request.headers()), &trusted).or(peer),
+            https: match request
+                .headers()
+                .get(X_FORWARDED_PROTO)
+                .and_then(|proto| proto.to_str().ok())
+            {
+                Some(proto) => proto
+                    .split(',')
+                    .next()
+                    .is_some_and(|p| p.trim().eq_ignore_ascii_case("https")),
+                None => direct_https,
+            },
+        }
+    } else {
+        request.headers_mut().remove(X_FORWARDED_FOR);
+        request.headers_mut().remove(X_FORWARDED_PROTO);
+        ClientInfo {
+            ip: peer,
+            https: direct_https,
+        }
+    };
+    request.extensions_mut().insert(info);
+    next.run(request).await
+}
+
+/// The rightmost hop that isn't one of ours. If every hop is one of ours,
+/// the leftmost is as close to the client as we'll get.
+fn forwarded_client(forwarded: &[IpAddr], trusted: &[TrustedProxy]) -> Option<IpAddr> {
+    forwarded
+        .iter()
+        .rev()
+        .find(|ip| !trusted.iter().any(|proxy| proxy.contains(**ip)))
+        .or(forwarded.first())
+        .copied()
+}
+
+fn forwarded_for(headers: &HeaderMap) -> Vec<IpAddr> {
+    headers
+        .get_all(X_FORWARDED_FOR)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|ip| IpAddr::from_str(ip.trim()).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn proxy(s: &str) -> TrustedProxy {
+        TrustedProxy::from_str(s).unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        IpAddr::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn parses_addresses_and_blocks() {
+        assert_eq!(proxy("10.0.0.1").prefix, 32);
+        assert_eq!(proxy("::1").prefix, 128);
+        assert_eq!(proxy("[::1]").prefix, 128);
+        assert_eq!(proxy("10.0.0.0/8").prefix, 8);
+        assert_eq!(proxy("fd00::/8").prefix, 8);
+        for bad in [
+            "10.0.0.0/33",
+            "::/129",
+            "10.0.0.0/",
+            "10.0.0.0/x",
+            "proxy.local",
+        ] {
+            assert!(TrustedProxy::from_str(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn contains_its_block() {
+        let block = proxy("192.168.1.0/24");
+        assert!(block.contains(ip("192.168.1.200")));
+        assert!(!block.contains(ip("192.168.2.1")));
+        // Mapped IPv4 is still IPv4.
+        assert!(block.contains(ip("::ffff:192.168.1.7")));
+        assert!(!block.contains(ip("fd00::1")));
+    }
+
+    #[test]
+    fn zero_prefix_is_everything() {
+        assert!(proxy("0.0.0.0/0").contains(ip("203.0.113.9")));
+        assert!(!proxy("0.0.0.0/0").contains(ip("2001:db8::1")));
+        assert!(proxy("::/0").contains(ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn full_prefix_is_one_address() {
+        let one = proxy("2001:db8::1/128");
+        assert!(one.contains(ip("2001:db8::1")));
+        assert!(!one.contains(ip("2001:db8::2")));
+        let one = proxy("10.1.2.3/32");
+        assert!(one.contains(ip("10.1.2.3")));
+        assert!(!one.contains(ip("10.1.2.4")));
+    }
+
+    #[test]
+    fn walks_forwarded_for_right_to_left() {
+        let trusted = [proxy("10.0.0.0/8")];
+        let hops = [ip("198.51.100.1"), ip("203.0.113.9"), ip("10.0.0.2")];
+        // Whatever the client claimed on the left, the first hop we don't run is who we were handed.
+        assert_eq!(forwarded_client(&hops, &trusted), Some(ip("203.0.113.9")));
+        assert_eq!(forwarded_client(&[], &trusted), None);
+    }
+
+    #[test]
+    fn takes_the_leftmost_when_every_hop_is_ours() {
+        let trusted = [proxy("10.0.0.0/8"), proxy("fd00::/8")];
+        let hops = [ip("10.0.0.9"), ip("fd00::2"), ip("10.0.0.2")];
+        assert_eq!(forwarded_client(&hops, &trusted), Some(ip("10.0.0.9")));
+    }
+}
@@ -1,46 +1,436 @@
+use std::future::Future;
 use std::net::SocketAddr;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::{Path as FsPath, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
-use axum::{extract::Path, response::Redirect, routing::get, Router};
+use axum::{
+    extract::{Extension, Path},
+    http::{StatusCode, Uri},
+    response::{IntoResponse, Redirect, Response},
+    routing::get,
+    Router,
+};
 use axum_server::tls_rustls::RustlsConfig;
+use axum_server::Handle;
 
-use crate::conf::{Config, HTTPSConfig};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use hyper_util::service::TowerToHyperService;
 
-pub async fn http_server(config: Config) -> Result<(), std::io::Error> {
-    let addr = SocketAddr::from((
-        config.routing.get("http").unwrap().0,
-        config.routing.get("https").unwrap().1,
-    ));
-    let app = Router::new().route(
-        "/*a",
-        get(move |Path(a): Path<String>| http_upgrade(a, config.service.uri)),
-    );
-    axum_server::bind(addr).serve(app.into_make_service()).await
+use socket2::{Domain, Protocol, Socket, Type};
+
+use tokio::net::UnixListener;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use tokio::{task, time};
+
+use crate::acme::{self, Challenges};
+use crate::app;
+use crate::conf::{Config, HTTPSConfig, UnixConfig};
+use crate::proxy::{ServedOverTls, UnixPeer};
+use crate::spotify::SpotifyClient;
+use crate::systemd;
+
+/// How often to look at the certificate files for changes.
+const CERT_POLL: Duration = Duration::from_secs(30);
+
+/// How long in-flight requests get to finish once we've been asked to stop.
+pub const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Runs the whole server off one client until SIGTERM or Ctrl-C: binds (or inherits) the listeners,
+/// gets a certificate if ACME's on, serves the router everywhere, and drains on the way out.
+/// Expects the client to have been started already.
+pub async fn run(spotify: Arc<SpotifyClient>) -> Result<(), String> {
+    let config = spotify.config().clone();
+    let app = app::router_with_client(spotify.clone());
+    let listeners = systemd::inherited_listeners()
+        .and_then(|inherited| Listeners::bind(&config, inherited))
+        .map_err(|e| format!("Failed to set up listeners: {}", e))?;
+    let (stop, stopping) = watch::channel(false);
+    task::spawn(async move {
+        shutdown_signal().await;
+        tracing::info!(
+            "Shutting down, giving in-flight requests {}s to finish",
+            DRAIN_TIMEOUT.as_secs()
+        );
+        systemd::stopping();
+        let _ = stop.send(true);
+    });
+    let unix = async {
+        match listeners.unix {
+            Some(unix) => unix_server(unix, app.clone(), stopping.clone()).await,
+            None => Ok(()),
+        }
+    };
+    let served = match config.https.clone() {
+        Some(https_config) => {
+            let challenges = Arc::new(Challenges::default());
+            // The plain HTTP side has to be up before ACME can validate anything.
+            let upgrade = match listeners.http.is_empty() {
+                false => Some(task::spawn(http_server(
+                    listeners.http,
+                    config.clone(),
+                    challenges.clone(),
+                    stopping.clone(),
+                ))),
+                true => None,
+            };
+            if let Some(acme_config) = &config.acme {
+                acme::ensure_certificate(acme_config, &challenges)
+                    .await
+                    .map_err(|e| format!("Failed to get a certificate: {}", e))?;
+            }
+            let tls_config = RustlsConfig::from_pem_file(&https_config.cert, &https_config.key)
+                .await
+                .map_err(|e| {
+                    format!(
+                        "Failed to load {} and {}: {}",
+                        https_config.cert.display(),
+                        https_config.key.display(),
+                        e
+                    )
+                })?;
+            spawn_cert_reload(https_config, tls_config.clone());
+            if let Some(acme_config) = &config.acme {
+                acme::spawn_renewal(acme_config.clone(), challenges, tls_config.clone());
+            }
+            systemd::ready();
+            tokio::try_join!(
+                https_server(listeners.https, tls_config, app.clone(), stopping.clone()),
+                async {
+                    match upgrade {
+                        Some(upgrade) => upgrade.await.map_err(std::io::Error::other)?,
+                        None => Ok(()),
+                    }
+                },
+                unix
+            )
+            .map(|_| ())
+        }
+        None => {
+            systemd::ready();
+            tokio::try_join!(
+                plain_server(listeners.http, app.clone(), stopping.clone()),
+                unix
+            )
+            .map(|_| ())
+        }
+    };
+
+    // If the refresher swapped in tokens it couldn't write at the time, have another go on the way down.
+    // (Only if the store's still there, so this can't undo a logout.)
+    spotify.persist_tokens();
+    served.map_err(|e| format!("Listener failed: {}", e))
+}
+
+/// Everything we'll be listening on, bound (or inherited from systemd) before anything starts serving,
+/// so a bad address fails loudly up front.
+#[derive(Default)]
+pub struct Listeners {
+    pub http: Vec<std::net::TcpListener>,
+    pub https: Vec<std::net::TcpListener>,
+    pub unix: Option<std::os::unix::net::UnixListener>,
+}
+
+impl Listeners {
+    /// Binds whatever the config asks for, except where systemd already handed us sockets for that scheme.
+    pub fn bind(config: &Config, inherited: Listeners) -> std::io::Result<Listeners> {
+        Ok(Listeners {
+            http: tcp_listeners(config.routing.get("http"), inherited.http)?,
+            https: tcp_listeners(config.routing.get("https"), inherited.https)?,
+            unix: match (inherited.unix, &config.unix) {
+                (Some(unix), _) => Some(unix),
+                (None, Some(unix)) => Some(unix_listener(unix)?),
+                (None, None) => None,
+            },
+        })
+    }
+}
+
+fn tcp_listeners(
+    addrs: Option<&Vec<SocketAddr>>,
+    inherited: Vec<std::net::TcpListener>,
+) -> std::io::Result<Vec<std::net::TcpListener>> {
+    if !inherited.is_empty() {
+        return Ok(inherited);
+    }
+    let addrs = addrs.cloned().unwrap_or_default();
+    addrs.iter().map(|&addr| bind_tcp(addr, &addrs)).collect()
+}
+
+fn unix_listener(unix: &UnixConfig) -> std::io::Result<std::os::unix::net::UnixListener> {
+    // A socket left behind by an earlier run would make the bind fail. Anything else there is
+    // somebody's file and the config's wrong, so leave it be.
+    match std::fs::symlink_metadata(&unix.path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(&unix.path)?,
+        Ok(_) => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!(
+                    "{} is already there and isn't a socket",
+                    unix.path.display()
+                ),
+            ))
+        }
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+        Err(_) => (),
+    }
+    let listener = std::os::unix::net::UnixListener::bind(&unix.path)?;
+    if let Some(mode) = unix.mode {
+        std::fs::set_permissions(&unix.path, std::fs::Permissions::from_mode(mode))?;
+    }
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
+
+pub async fn http_server(
+    listeners: Vec<std::net::TcpListener>,
+    config: Config,
+    challenges: Arc<Challenges>,
+    stopping: watch::Receiver<bool>,
+) -> Result<(), std::io::Error> {
+    let webroot = config.https.as_ref().and_then(|h| h.acme_webroot.clone());
+    let app = Router::new()
+        .route(
+            "/.well-known/acme-challenge/:token",
+            get(move |Path(token): Path<String>| acme_challenge(challenges, webroot, token)),
+        )
+        .fallback(move |uri: Uri| http_upgrade(uri, config.uri));
+    serve_all(listeners.into_iter().map(|listener| {
+        let handle = drain_on_stop(stopping.clone());
+        axum_server::from_tcp(listener)
+            .handle(handle)
+            .serve(app.clone().into_make_service())
+    }))
+    .await
+}
+
+/// Serves the app itself over plain HTTP, for when there's no [https] (or something else terminates TLS).
+pub async fn plain_server(
+    listeners: Vec<std::net::TcpListener>,
+    app: Router<()>,
+    stopping: watch::Receiver<bool>,
+) -> Result<(), std::io::Error> {
+    serve_all(listeners.into_iter().map(|listener| {
+        let handle = drain_on_stop(stopping.clone());
+        axum_server::from_tcp(listener).handle(handle).serve(
+            app.clone()
+                .into_make_service_with_connect_info::<SocketAddr>(),
+        )
+    }))
+    .await
 }
 
-pub async fn http_upgrade(a: String, uri: String) -> Redirect {
-    let uri = format!("https://{}{}", uri, a);
+/// Serves the app over a Unix socket, for a reverse proxy on the same machine.
+/// axum-server only does TCP, so this drives hyper directly, draining the same way axum-server does.
+pub async fn unix_server(
+    listener: std::os::unix::net::UnixListener,
+    app: Router<()>,
+    stopping: watch::Receiver<bool>,
+) -> Result<(), std::io::Error> {
+    let listener = UnixListener::from_std(listener)?;
+    let service = TowerToHyperService::new(app.layer(Extension(UnixPeer)));
+    let mut connections = JoinSet::new();
+    loop {
+        let stream = tokio::select! {
+            accepted = listener.accept() => accepted?.0,
+            _ = stopped(stopping.clone()) => break,
+        };
+        let service = service.clone();
+        let stopping = stopping.clone();
+        connections.spawn(async move {
+            let builder = auto::Builder::new(TokioExecutor::new());
+            let connection = builder.serve_connection(TokioIo::new(stream), service);
+            tokio::pin!(connection);
+            tokio::select! {
+                _ = connection.as_mut() => return,
+                _ = stopped(stopping) => connection.as_mut().graceful_shutdown(),
+            }
+            let _ = connection.await;
+        });
+        // Don't let finished connections pile up.
+        while connections.try_join_next().is_some() {}
+    }
+    // Whatever's still going after the timeout gets cut off when the JoinSet drops.
+    let _ = time::timeout(DRAIN_TIMEOUT, async {
+        while connections.join_next().await.is_some() {}
+    })
+    .await;
+    Ok(())
+}
+
+/// Bounces plain HTTP over to HTTPS, keeping the path and query string intact.
+pub async fn http_upgrade(a: Uri, uri: String) -> Redirect {
+    let uri = format!(
+        "https://{}{}",
+        uri,
+        a.path_and_query().map_or("/", |pq| pq.as_str())
+    );
     axum::response::Redirect::temporary(uri.as_str())
 }
 
+/// Hands out HTTP-01 challenge responses, either our own or from a certbot-style webroot,
+/// so certificates can keep renewing while everything else gets upgraded.
+async fn acme_challenge(
+    challenges: Arc<Challenges>,
+    webroot: Option<PathBuf>,
+    token: String,
+) -> Response {
+    if let Some(key_authorization) = challenges.get(&token) {
+        return key_authorization.into_response();
+    }
+    let webroot = match webroot {
+        Some(webroot) => webroot,
+        None => return StatusCode::NOT_FOUND.into_response(),
+    };
+    // Tokens are base64url; anything else is somebody poking at the filesystem.
+    if token.is_empty()
+        || !token
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    match tokio::fs::read(webroot.join(".well-known/acme-challenge").join(token)).await {
+        Ok(contents) => contents.into_response(),
+        Err(_) => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
 pub async fn https_server(
-    https_config: HTTPSConfig,
+    listeners: Vec<std::net::TcpListener>,
+    tls_config: RustlsConfig,
     app: Router<()>,
-    config: Config,
+    stopping: watch::Receiver<bool>,
 ) -> Result<(), std::io::Error> {
-    let tls_config = match RustlsConfig::from_pem_file(https_config.cert, https_config.key).await {
-        Ok(a) => a,
-        Err(e) => return Err(e),
+    let app = app.layer(Extension(ServedOverTls));
+    serve_all(listeners.into_iter().map(|listener| {
+        let handle = drain_on_stop(stopping.clone());
+        axum_server::from_tcp_rustls(listener, tls_config.clone())
+            .handle(handle)
+            .serve(
+                app.clone()
+                    .into_make_service_with_connect_info::<SocketAddr>(),
+            )
+    }))
+    .await
+}
+
+/// Opens a listening socket for one of a scheme's addresses.
+/// An IPv6 wildcard normally grabs IPv4 too (dual-stack), which would collide with an
+/// IPv4 listener on the same port, so in that case the IPv6 socket sticks to IPv6.
+fn bind_tcp(addr: SocketAddr, siblings: &[SocketAddr]) -> std::io::Result<std::net::TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_reuse_address(true)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(
+            siblings
+                .iter()
+                .any(|other| other.is_ipv4() && other.port() == addr.port()),
+        )?;
+    }
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket.into())
+}
+
+/// Runs a server per listener until one of them falls over.
+async fn serve_all<F>(servers: impl Iterator<Item = F>) -> Result<(), std::io::Error>
+where
+    F: Future<Output = std::io::Result<()>> + Send + 'static,
+{
+    let mut running = JoinSet::new();
+    for server in servers {
+        running.spawn(server);
+    }
+    while let Some(result) = running.join_next().await {
+        result.map_err(std::io::Error::other)??;
+    }
+    Ok(())
+}
+
+/// Resolves on SIGTERM or Ctrl-C.
+pub async fn shutdown_signal() {
+    let terminate = async {
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(_) => std::future::pending().await,
+        }
     };
-    let addr = SocketAddr::from((
-        config.routing.get("https").unwrap().0,
-        config.routing.get("https").unwrap().1,
-    ));
-    match axum_server::bind_rustls(addr, tls_config)
-        .serve(app.into_make_service())
-        .await
-    {
-        _ => Ok(()), // this is a gross way of getting rust to stop yelling at us for not handling errors.
-                     // to be fair, this is also a gross way of (not) handling errors.
-                     // FIXME: handle errors (sigh)
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => (),
+        _ = terminate => (),
+    }
+}
+
+/// Resolves once we've been told to stop. If the sender goes away without saying so, it never does.
+async fn stopped(mut stopping: watch::Receiver<bool>) {
+    if stopping.wait_for(|stop| *stop).await.is_err() {
+        std::future::pending().await
     }
 }
+
+/// A Handle for one axum-server that stops accepting and starts draining once we're told to stop.
+/// Each server gets its own, since a Handle only passes the word on to servers that are already listening.
+fn drain_on_stop(stopping: watch::Receiver<bool>) -> Handle {
+    let handle = Handle::new();
+    let draining = handle.clone();
+    task::spawn(async move {
+        if draining.listening().await.is_some() {
+            stopped(stopping).await;
+            draining.graceful_shutdown(Some(DRAIN_TIMEOUT));
+        }
+    });
+    handle
+}
+
+/// Swaps renewed certificates into the running listener, so certbot doesn't cost us our tokens.
+/// Reloads when the cert or key files change (once they've stopped changing for a poll, so we
+/// don't catch certbot halfway through), or right away on SIGHUP.
+/// A reload that fails leaves the old certificate in place.
+pub fn spawn_cert_reload(https_config: HTTPSConfig, tls_config: RustlsConfig) {
+    task::spawn(async move {
+        let mut hangup = signal(SignalKind::hangup()).ok();
+        let mut loaded = modified(&https_config);
+        let mut seen = loaded;
+        let mut interval = time::interval(CERT_POLL);
+        loop {
+            let forced = tokio::select! {
+                _ = interval.tick() => false,
+                Some(_) = async { hangup.as_mut()?.recv().await } => true,
+            };
+
+            let current = modified(&https_config);
+            if forced || (current != loaded && current == seen) {
+                match tls_config
+                    .reload_from_pem_file(&https_config.cert, &https_config.key)
+                    .await
+                {
+                    Ok(()) => {
+                        tracing::info!(
+                            cert = %https_config.cert.display(),
+                            "Reloaded TLS certificate"
+                        );
+                        loaded = current;
+                    }
+                    Err(e) => tracing::warn!(
+                        "Failed to reload TLS certificate, keeping the old one: {}",
+                        e
+                    ),
+                }
+            }
+            seen = current;
+        }
+    });
+}
+
+fn modified(https_config: &HTTPSConfig) -> (Option<SystemTime>, Option<SystemTime>) {
+    let mtime = |path: &FsPath| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    (mtime(&https_config.cert), mtime(&https_config.key))
+}
@@ -1,174 +1,826 @@
+use reqwest::header;
+use reqwest::{self, Method, Response, StatusCode, Url};
 
-use reqwest::{self, Response, Url};
-use reqwest::{header};
-
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fmt::Debug;
 use std::fs;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
-use serde::Deserialize;
+use serde::de::DeserializeOwned;
 use serde_json::{self, Value};
 
-const API_BASE: &str = "https://api.spotify.com/v1/";
-const ACCOUNTS_BASE: &str = "https://accounts.spotify.com/";
+use tokio::{task, time};
+use tracing::Instrument;
+
+use crate::authstate::{AuthState, TokenSet};
+use crate::conf::{Config, FixtureMode, Service, ServiceAuth};
+use crate::fixtures;
+use crate::metrics::{self, TokenKind};
+use crate::models::{
+    Artist, CurrentlyPlaying, CursorPage, Page, PlayHistory, PlaybackState, Playlist,
+    SimplifiedPlaylist, TimeRange, Track,
+};
+use crate::store;
+
+pub const API_BASE: &str = "https://api.spotify.com/v1/";
+pub const ACCOUNTS_BASE: &str = "https://accounts.spotify.com/";
 const API_URL: &str = "api/token";
 const AUTH_URL: &str = "authorize";
+/// Everything the player endpoints can tell us about. Audiobook chapters come back as episodes.
+const ADDITIONAL_TYPES: &str = "additional_types=track,episode";
+
+/// Scopes Spotify wants for the endpoints people are most likely to proxy.
+/// Matched by path prefix, first hit wins, so keep the more specific entries on top.
+/// https://developer.spotify.com/documentation/web-api/concepts/scopes
+const ENDPOINT_SCOPES: &[(&str, &[&str])] = &[
+    (
+        "me/player/currently-playing",
+        &["user-read-currently-playing"],
+    ),
+    ("me/player/recently-played", &["user-read-recently-played"]),
+    ("me/player", &["user-read-playback-state"]),
+    ("me/top", &["user-top-read"]),
+    ("me/tracks", &["user-library-read"]),
+    ("me/albums", &["user-library-read"]),
+    ("me/shows", &["user-library-read"]),
+    (
+        "me/episodes",
+        &["user-library-read", "user-read-playback-position"],
+    ),
+    ("me/audiobooks", &["user-library-read"]),
+    ("me/playlists", &["playlist-read-private"]),
+    ("me/following", &["user-follow-read"]),
+    ("me", &["user-read-private"]),
+];
+
+/// The only endpoints a service may write to, with the method Spotify wants and the scopes it needs.
+/// Matched exactly: anything that changes what's on the account goes on this list first.
+pub const WRITE_ENDPOINTS: &[(&str, Method, &[&str])] = &[
+    (
+        "me/player/queue",
+        Method::POST,
+        &["user-modify-playback-state"],
+    ),
+    ("me/tracks", Method::PUT, &["user-library-modify"]),
+];
+
+/// Talks to Spotify, or whatever's standing in for it: the base URLs come from [spotify],
+/// so tests can point everything at a mock, and API calls can be recorded to or replayed from fixtures.
+/// Also owns everything a running server needs to stay logged in: the client credentials,
+/// and the user and app tokens along with the tasks that keep them fresh.
+/// One of these (in an Arc) is all the router needs.
+pub struct SpotifyClient {
+    config: Arc<Config>,
+    credentials: Credentials,
+    api_base: String,
+    accounts_base: String,
+    http: reqwest::Client,
+    user: AuthState,
+    app: AuthState,
+    /// Held while fetching an app token, so a burst of requests makes one trip to Spotify, not one each.
+    app_refresh: tokio::sync::Mutex<()>,
+    /// How many times we've asked for an app token, so anyone who waited on the lock can tell they've been beaten to it.
+    app_attempts: AtomicU64,
+}
 
 #[derive(Debug, Clone)]
 pub struct Credentials {
     pub username: String,
     pub password: Option<String>,
 }
-#[derive(Deserialize)]
-pub struct AuthReqResponse {
-    pub code: String,
-    pub state: String,
-}
-
-pub fn read_creds_from_file(filename: Option<&str>) -> Credentials {
-    let file_content = fs::read_to_string(match filename {
-        Some(string) => string,
-        None => "api_keys/spotify_client.txt",
-    })
-    .expect("Failed to read the file containing the client credentials!");
-    let out = file_content.split_once(":");
-    match out {
-        Some(split) => Credentials {
-            username: split.0.to_owned(),
-            password: Some(split.1.trim().to_owned()),
+/// Reads `id:secret` (or just an ID) from `filename`, api_keys/spotify_client.txt if there isn't one.
+pub fn read_creds_from_file(filename: Option<&str>) -> Result<Credentials, String> {
+    let filename = filename.unwrap_or("api_keys/spotify_client.txt");
+    let file_content = fs::read_to_string(filename).map_err(|e| {
+        format!(
+            "Failed to read the client credentials from {}: {} (or set client_id and client_secret under [spotify])",
+            filename, e
+        )
+    })?;
+    Ok(match file_content.trim().split_once(':') {
+        Some((id, secret)) => Credentials {
+            username: id.to_owned(),
+            password: Some(secret.to_owned()),
         },
         None => Credentials {
-            username: file_content,
+            username: file_content.trim().to_owned(),
             password: None,
         },
-    }
+    })
 }
 
-pub fn read_token_from_file(filename: Option<&str>) -> Credentials {
-    Credentials {
-        username: fs::read_to_string(match filename {
-            Some(string) => string,
-            None => "api_keys/spotify_token.txt",
-        })
-        .expect("Failed to read the file with the OAuth token."),
-        password: None,
+impl SpotifyClient {
+    pub fn new(config: Arc<Config>) -> SpotifyClient {
+        SpotifyClient {
+            api_base: with_trailing_slash(&config.spotify.api_base),
+            accounts_base: with_trailing_slash(&config.spotify.accounts_base),
+            http: build_client(None).expect("Failed to initialize HTTP client!"),
+            credentials: config.spotify.credentials.clone(),
+            config,
+            user: AuthState::default(),
+            app: AuthState::default(),
+            app_refresh: tokio::sync::Mutex::new(()),
+            app_attempts: AtomicU64::new(0),
+        }
     }
-}
 
-pub fn read_client_from_file(filename: Option<&str>) -> Credentials {
-    Credentials {
-        username: fs::read_to_string(match filename {
-            Some(string) => string,
-            None => "api_keys/spotify_client.txt",
-        })
-        .expect("Failed to read the file with the OAuth token.")
-        .split_once(":")
-        .unwrap()
-        .0
-        .to_string(),
-        password: None,
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn user_tokens(&self) -> &AuthState {
+        &self.user
     }
-}
 
-pub async fn get_api_endpoint(accounts: bool, api_key: &str, api_endpoint: &str) -> Response {
-    build_client(None)
-        .expect("Failed to initialize HTTP client!")
-        .get((if accounts { ACCOUNTS_BASE } else { API_BASE }).to_owned() + api_endpoint)
-        .header("Authorization", format!("Bearer {}", api_key))
-        .send()
+    pub fn app_tokens(&self) -> &AuthState {
+        &self.app
+    }
+
+    /// Whichever tokens the service calls Spotify with.
+    pub fn tokens_for(&self, auth: ServiceAuth) -> &AuthState {
+        match auth {
+            ServiceAuth::User => &self.user,
+            ServiceAuth::App => &self.app,
+        }
+    }
+
+    /// Gets the tokens going: picks up whatever's in the store (or makes some up when replaying),
+    /// and starts fetching app tokens if any service wants them.
+    pub fn start(self: &Arc<Self>) {
+        if let Some(FixtureMode::Replay(dir)) = &self.config.spotify.fixtures {
+            // Fixtures don't care what token they're asked with, so there's nobody to log in.
+            tracing::warn!(dir = %dir.display(), "Replaying Spotify responses from fixtures");
+            self.user.swap(TokenSet {
+                access_token: String::from("replay"),
+                refresh_token: None,
+                scopes: self
+                    .config
+                    .requested_scopes()
+                    .into_iter()
+                    .map(str::to_owned)
+                    .collect(),
+                expires_at: SystemTime::now() + Duration::from_secs(365 * 24 * 60 * 60),
+            });
+        } else if let Err(e) = self.reload_tokens() {
+            tracing::warn!("{}", e);
+        }
+        if self
+            .config
+            .services
+            .iter()
+            .any(|service| service.auth == ServiceAuth::App)
+        {
+            let client = self.clone();
+            task::spawn(async move {
+                loop {
+                    let wait = if client.refresh_app_token().await {
+                        client
+                            .app
+                            .tokens()
+                            .map(|t| t.refresh_in())
+                            .unwrap_or(Duration::ZERO)
+                    } else {
+                        Duration::from_secs(30)
+                    };
+                    time::sleep(wait).await;
+                }
+            });
+        }
+    }
+
+    /// Calls the service's endpoint (as filled in for this request) with whichever token it uses,
+    /// and notes how it went. Err if Spotify couldn't be reached at all.
+    pub async fn get_service(&self, service: &Service, endpoint: &str) -> Result<Response, String> {
+        let span = tracing::info_span!(
+            "upstream",
+            service = %service.name,
+            endpoint = %endpoint
+        );
+        async {
+            let tokens = self.tokens_for(service.auth);
+            let token = tokens.access_token().unwrap_or_default();
+            let accounts = service.target == "accounts";
+            let resp = self
+                .get_upstream(tokens, accounts, &token, endpoint)
+                .await?;
+            Ok(match service.paginate {
+                Some(max_items) if resp.status() == StatusCode::OK => {
+                    self.gather_pages(tokens, accounts, &token, resp, max_items)
+                        .await
+                }
+                _ => resp,
+            })
+        }
+        .instrument(span)
         .await
-        .expect("Failed to send GET request!") // TODO: Make this log specific endpoint
-}
+    }
+
+    /// One call upstream, logged and counted.
+    async fn get_upstream(
+        &self,
+        tokens: &AuthState,
+        accounts: bool,
+        token: &str,
+        endpoint: &str,
+    ) -> Result<Response, String> {
+        let started = Instant::now();
+        let resp = self.get_api_endpoint(accounts, token, endpoint).await?;
+        tracing::info!(
+            endpoint,
+            status = resp.status().as_u16(),
+            latency_ms = started.elapsed().as_millis() as u64,
+            "Spotify responded"
+        );
+        metrics::upstream(resp.status());
+        tokens.record_upstream(resp.status().as_u16());
+        Ok(resp)
+    }
+
+    /// Follows a page's `next` links until there are none left or we've got max_items,
+    /// and hands back the first page with everything in its `items`.
+    /// An empty page or a link we've already followed ends it too, so a confused API can't keep us going forever.
+    /// A page that fails partway through is passed back as is, rather than a list with a hole in it.
+    async fn gather_pages(
+        &self,
+        tokens: &AuthState,
+        accounts: bool,
+        token: &str,
+        first: Response,
+        max_items: usize,
+    ) -> Response {
+        let mut headers = first.headers().clone();
+        // The body's about to be a lot longer.
+        headers.remove(header::CONTENT_LENGTH);
+        let mut page = match first.json::<Value>().await {
+            Ok(page) => page,
+            Err(e) => {
+                tracing::warn!("Failed to parse the first page: {}", e);
+                return fixtures::rebuild(StatusCode::BAD_GATEWAY, &headers, Vec::new());
+            }
+        };
+        let mut items = match page.get_mut("items").map(Value::take) {
+            Some(Value::Array(items)) => items,
+            // Not a page after all; nothing to follow.
+            _ => return fixtures::rebuild(StatusCode::OK, &headers, page.to_string().into_bytes()),
+        };
+        let mut next = page.get("next").and_then(Value::as_str).map(str::to_owned);
+        let mut followed = HashSet::new();
+        while items.len() < max_items {
+            let endpoint = match next.as_deref().map(|next| self.endpoint_from_link(next)) {
+                Some(Some(endpoint)) => endpoint,
+                Some(None) => {
+                    tracing::warn!(next, "Not following a next link that leaves the API");
+                    break;
+                }
+                None => break,
+            };
+            if !followed.insert(endpoint.clone()) {
+                tracing::warn!(
+                    endpoint,
+                    "Not following a next link we've already been down"
+                );
+                break;
+            }
+            let resp = match self.get_upstream(tokens, accounts, token, &endpoint).await {
+                Ok(resp) => resp,
+                Err(e) => {
+                    tracing::warn!("{}", e);
+                    return fixtures::rebuild(StatusCode::BAD_GATEWAY, &headers, Vec::new());
+                }
+            };
+            if resp.status() != StatusCode::OK {
+                return resp;
+            }
+            let mut following = match resp.json::<Value>().await {
+                Ok(following) => following,
+                Err(e) => {
+                    tracing::warn!(endpoint, "Failed to parse a page: {}", e);
+                    return fixtures::rebuild(StatusCode::BAD_GATEWAY, &headers, Vec::new());
+                }
+            };
+            match following.get_mut("items").map(Value::take) {
+                Some(Value::Array(more)) if !more.is_empty() => items.extend(more),
+                _ => break,
+            }
+            next = following
+                .get("next")
+                .and_then(Value::as_str)
+                .map(str::to_owned);
+        }
+        items.truncate(max_items);
+        page["items"] = Value::Array(items);
+        page["next"] = Value::Null;
+        fixtures::rebuild(StatusCode::OK, &headers, page.to_string().into_bytes())
+    }
+
+    /// Turns a `next` link back into an endpoint, as long as it points at the API we're talking to:
+    /// our token goes wherever it points, so nowhere else will do.
+    fn endpoint_from_link(&self, link: &str) -> Option<String> {
+        if let Some(endpoint) = link.strip_prefix(&self.api_base) {
+            return Some(endpoint.to_owned());
+        }
+        // Recorded links point wherever the recording was made, but replaying never goes upstream anyway.
+        if self.config.spotify.replaying() {
+            let link = Url::parse(link).ok()?;
+            let base = Url::parse(&self.api_base).ok()?;
+            let endpoint = link.path().strip_prefix(base.path())?;
+            return Some(match link.query() {
+                Some(query) => format!("{}?{}", endpoint, query),
+                None => endpoint.to_owned(),
+            });
+        }
+        None
+    }
 
-/*
-https://developer.spotify.com/documentation/web-api/reference/#/operations/get-the-users-currently-playing-track
-body -> item -> id will yield the spotify ID for the currently playing track
-*/
-
-// https://developer.spotify.com/documentation/general/guides/authorization/client-credentials/
-pub async fn get_client_credentials(creds: Credentials) -> reqwest::Response {
-    let client: reqwest::Client = build_client(None).expect("Failed to initialize HTTP client");
-    let mut params: HashMap<&str, &str> = HashMap::new();
-    params.insert("grant_type", "client_credentials");
-    client
-        .post(ACCOUNTS_BASE.to_owned() + API_URL)
-        .form(&params)
-        .basic_auth(creds.username, creds.password)
-        .send()
+    /// GETs an API endpoint with the user's token and parses whatever comes back.
+    /// A 204 is None: Spotify's way of saying there's nothing playing.
+    pub async fn get_json<T: DeserializeOwned>(&self, endpoint: &str) -> Result<Option<T>, String> {
+        let resp = self
+            .get_api_endpoint(
+                false,
+                &self.user.access_token().unwrap_or_default(),
+                endpoint,
+            )
+            .await?;
+        metrics::upstream(resp.status());
+        self.user.record_upstream(resp.status().as_u16());
+        match resp.status() {
+            StatusCode::NO_CONTENT => Ok(None),
+            status if status.is_success() => resp
+                .json::<T>()
+                .await
+                .map(Some)
+                .map_err(|e| format!("Failed to parse {}: {}", endpoint, e)),
+            status => Err(format!("Spotify answered {} with {}", endpoint, status)),
+        }
+    }
+
+    /// Tracks and episodes both; only ads come back without an item.
+    pub async fn currently_playing(&self) -> Result<Option<CurrentlyPlaying>, String> {
+        self.get_json(&with_additional_types(String::from(
+            "me/player/currently-playing",
+        )))
         .await
-        .expect("Failed to send POST request!")
-}
+    }
 
-// https://developer.spotify.com/documentation/general/guides/authorization/code-flow/
-pub fn get_authorization_code(
-    creds: Credentials,
-    scopes: Option<Vec<&str>>,
-    redirect: &str,
-) -> Url {
-    let scps; // UGH lifetimes
-    let mut params: HashMap<&str, &str> = HashMap::new();
-    params.insert("client_id", creds.username.as_str());
-    params.insert("response_type", "code");
-    params.insert("redirect_uri", redirect);
-    let _unused = match scopes {
-        Some(scope) => {
-            scps = scope.join(" ");
-            params.insert("scope", &scps.as_str())
-        }
-        None => None,
-    };
-
-    let param_string: String = params
-        .iter()
-        .map(|(k, v)| format!("{}={}&", k, v))
-        .collect::<String>();
+    /// None when there's no active device.
+    pub async fn playback_state(&self) -> Result<Option<PlaybackState>, String> {
+        self.get_json(&with_additional_types(String::from("me/player")))
+            .await
+    }
 
-    let mut chars = param_string.as_str().chars();
-    chars.next_back();
+    pub async fn recently_played(&self, limit: u32) -> Result<CursorPage<PlayHistory>, String> {
+        self.get_body(&format!("me/player/recently-played?limit={}", limit))
+            .await
+    }
 
-    let param_string = chars.as_str();
+    pub async fn top_tracks(&self, range: TimeRange, limit: u32) -> Result<Page<Track>, String> {
+        self.get_body(&format!(
+            "me/top/tracks?time_range={}&limit={}",
+            range.as_str(),
+            limit
+        ))
+        .await
+    }
 
-    let mut url_string: String = String::new();
-    url_string.push_str(ACCOUNTS_BASE);
-    url_string.push_str(AUTH_URL);
-    url_string.push_str("?");
-    url_string.push_str(
-        param_string, // a: &str, b: String; a+b = ?
-    );
-    Url::parse(url_string.as_str()).unwrap()
-}
+    pub async fn top_artists(&self, range: TimeRange, limit: u32) -> Result<Page<Artist>, String> {
+        self.get_body(&format!(
+            "me/top/artists?time_range={}&limit={}",
+            range.as_str(),
+            limit
+        ))
+        .await
+    }
 
-// https://developer.spotify.com/documentation/web-api/tutorials/code-flow
-pub async fn redeem_authorization_code_for_access_token(
-    authorization_code: &str,
-    creds: Credentials,
-    redirect: &str,
-    refresh: bool,
-) -> reqwest::Response {
-    let mut params: HashMap<&str, &str> = HashMap::new();
-    params.insert(
-        "grant_type",
-        if refresh {
-            "refresh_token"
-        } else {
-            "authorization_code"
-        },
-    );
-    params.insert(
-        if !refresh { "code" } else { "refresh_token" },
-        authorization_code,
-    );
-    params.insert("redirect_uri", redirect);
-
-    return build_client(None)
-        .expect("Failed to initialize HTTP client")
-        .post(ACCOUNTS_BASE.to_owned() + API_URL)
-        .basic_auth(creds.username, creds.password)
-        .header("Content-Type", "application/x-www-form-urlencoded")
-        .form(&params)
-        .send()
+    pub async fn playlists(&self, limit: u32) -> Result<Page<SimplifiedPlaylist>, String> {
+        self.get_body(&format!("me/playlists?limit={}", limit))
+            .await
+    }
+
+    pub async fn playlist(&self, id: &str) -> Result<Playlist, String> {
+        self.get_body(&format!("playlists/{}", id)).await
+    }
+
+    /// Sends a write service's endpoint (as filled in for this request) with the user's token.
+    /// Everything the write endpoints take fits in the query, so the body's always empty.
+    /// Replaying just pretends it worked, and nothing gets recorded: there's nothing worth keeping in a 204.
+    pub async fn send_action(&self, service: &Service, endpoint: &str) -> Result<Response, String> {
+        let method = match &service.write {
+            Some(write) => write.method.clone(),
+            None => {
+                return Ok(fixtures::rebuild(
+                    StatusCode::METHOD_NOT_ALLOWED,
+                    &header::HeaderMap::new(),
+                    Vec::new(),
+                ))
+            }
+        };
+        let span = tracing::info_span!(
+            "upstream",
+            service = %service.name,
+            endpoint = %endpoint,
+            method = %method
+        );
+        async {
+            if self.config.spotify.replaying() {
+                tracing::info!(endpoint, "Not sending a write while replaying");
+                return Ok(fixtures::rebuild(
+                    StatusCode::NO_CONTENT,
+                    &header::HeaderMap::new(),
+                    Vec::new(),
+                ));
+            }
+            let started = Instant::now();
+            let resp = self
+                .http
+                .request(method, self.api_base.to_owned() + endpoint)
+                .header(
+                    "Authorization",
+                    format!("Bearer {}", self.user.access_token().unwrap_or_default()),
+                )
+                .header(header::CONTENT_LENGTH, 0)
+                .send()
+                .await
+                .map_err(|e| format!("Failed to send a write to Spotify: {}", e))?;
+            tracing::info!(
+                endpoint,
+                status = resp.status().as_u16(),
+                latency_ms = started.elapsed().as_millis() as u64,
+                "Spotify responded"
+            );
+            metrics::upstream(resp.status());
+            self.user.record_upstream(resp.status().as_u16());
+            Ok(resp)
+        }
+        .instrument(span)
         .await
-        .expect("Failed to send GET request!");
+    }
+
+    /// The client everything upstream goes through, for anything else that needs to make a request.
+    pub fn http(&self) -> &reqwest::Client {
+        &self.http
+    }
+
+    /// For the endpoints that always have a body.
+    async fn get_body<T: DeserializeOwned>(&self, endpoint: &str) -> Result<T, String> {
+        self.get_json(endpoint)
+            .await?
+            .ok_or_else(|| format!("Spotify answered {} with nothing", endpoint))
+    }
+
+    pub async fn get_api_endpoint(
+        &self,
+        accounts: bool,
+        api_key: &str,
+        api_endpoint: &str,
+    ) -> Result<Response, String> {
+        if let Some(FixtureMode::Replay(dir)) = &self.config.spotify.fixtures {
+            return Ok(fixtures::replay(dir, accounts, api_endpoint));
+        }
+        let response = self
+            .http
+            .get(
+                (if accounts {
+                    &self.accounts_base
+                } else {
+                    &self.api_base
+                })
+                .to_owned()
+                    + api_endpoint,
+            )
+            .header("Authorization", format!("Bearer {}", api_key))
+            .send()
+            .await
+            .map_err(|e| format!("Failed to reach {}: {}", api_endpoint, e))?;
+        Ok(match &self.config.spotify.fixtures {
+            Some(FixtureMode::Record(dir)) => {
+                fixtures::record(dir, accounts, api_endpoint, response).await
+            }
+            _ => response,
+        })
+    }
+
+    /*
+    https://developer.spotify.com/documentation/web-api/reference/#/operations/get-the-users-currently-playing-track
+    body -> item -> id will yield the spotify ID for the currently playing track
+    */
+
+    // https://developer.spotify.com/documentation/general/guides/authorization/client-credentials/
+    pub async fn get_client_credentials(&self) -> Result<Response, String> {
+        if self.config.spotify.replaying() {
+            return Ok(fixtures::token_response());
+        }
+        let mut params: HashMap<&str, &str> = HashMap::new();
+        params.insert("grant_type", "client_credentials");
+        self.http
+            .post(self.accounts_base.to_owned() + API_URL)
+            .form(&params)
+            .basic_auth(
+                &self.credentials.username,
+                self.credentials.password.as_ref(),
+            )
+            .send()
+            .await
+            .map_err(|e| format!("Failed to ask for a client-credentials token: {}", e))
+    }
+
+    /// Where to send someone to log in. The state comes back with them to /authorized.
+    pub fn authorization_url(&self, state: &str) -> Url {
+        let mut url = self.get_authorization_code(
+            Some(self.config.requested_scopes()),
+            self.config.redirect.as_str(),
+        );
+        url.query_pairs_mut().append_pair("state", state);
+        url
+    }
+
+    // https://developer.spotify.com/documentation/general/guides/authorization/code-flow/
+    pub fn get_authorization_code(&self, scopes: Option<Vec<&str>>, redirect: &str) -> Url {
+        let scps; // UGH lifetimes
+        let mut params: HashMap<&str, &str> = HashMap::new();
+        params.insert("client_id", self.credentials.username.as_str());
+        params.insert("response_type", "code");
+        params.insert("redirect_uri", redirect);
+        let _unused = match scopes {
+            Some(scope) => {
+                scps = scope.join(" ");
+                params.insert("scope", scps.as_str())
+            }
+            None => None,
+        };
+
+        let param_string: String = params
+            .iter()
+            .map(|(k, v)| format!("{}={}&", k, v))
+            .collect::<String>();
+
+        let mut chars = param_string.as_str().chars();
+        chars.next_back();
+
+        let param_string = chars.as_str();
+
+        let mut url_string: String = String::new();
+        url_string.push_str(&self.accounts_base);
+        url_string.push_str(AUTH_URL);
+        url_string.push('?');
+        url_string.push_str(
+            param_string, // a: &str, b: String; a+b = ?
+        );
+        Url::parse(url_string.as_str()).unwrap()
+    }
+
+    // https://developer.spotify.com/documentation/web-api/tutorials/code-flow
+    pub async fn redeem_authorization_code_for_access_token(
+        &self,
+        authorization_code: &str,
+        refresh: bool,
+    ) -> Result<Response, String> {
+        if self.config.spotify.replaying() {
+            return Ok(fixtures::token_response());
+        }
+        let mut params: HashMap<&str, &str> = HashMap::new();
+        params.insert(
+            "grant_type",
+            if refresh {
+                "refresh_token"
+            } else {
+                "authorization_code"
+            },
+        );
+        params.insert(
+            if !refresh { "code" } else { "refresh_token" },
+            authorization_code,
+        );
+        params.insert("redirect_uri", self.config.redirect.as_str());
+
+        self.http
+            .post(self.accounts_base.to_owned() + API_URL)
+            .basic_auth(
+                &self.credentials.username,
+                self.credentials.password.as_ref(),
+            )
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .form(&params)
+            .send()
+            .await
+            .map_err(|e| format!("Failed to reach the token endpoint: {}", e))
+    }
+
+    /// Trades an authorization code from the redirect for a set of user tokens.
+    /// Doesn't swap them in; the caller decides what to do with them.
+    pub async fn redeem_code(&self, code: &str) -> Result<TokenSet, String> {
+        let response = self
+            .redeem_authorization_code_for_access_token(code, false)
+            .await?;
+        if !response.status().is_success() {
+            return Err(format!(
+                "Failed to redeem the authorization code: {}",
+                response.status()
+            ));
+        }
+        let token_set = response
+            .json::<TokenSet>()
+            .await
+            .map_err(|e| format!("Failed to parse the token response: {}", e))?;
+        self.config.warn_on_missing_scopes(&token_set);
+        Ok(token_set)
+    }
+
+    /// Logs the server in with a freshly redeemed set of tokens and keeps them fresh from here on.
+    pub fn log_in(self: &Arc<Self>, token_set: TokenSet) {
+        self.user.swap(token_set);
+        self.save_tokens(true);
+        self.spawn_refresh();
+    }
+
+    /// Logs in with whatever's in the token store, if there's anything there, and keeps it fresh.
+    /// Says whether there was. This is how tokens from `obscurify auth` get into a running server.
+    pub fn reload_tokens(self: &Arc<Self>) -> Result<bool, String> {
+        let path = match &self.config.token_store {
+            Some(path) if !self.config.spotify.replaying() => path,
+            _ => return Ok(false),
+        };
+        match store::load(path) {
+            Ok(Some(token_set)) => {
+                self.config.warn_on_missing_scopes(&token_set);
+                self.user.swap(token_set);
+                self.user.mark_saved();
+                self.spawn_refresh();
+                Ok(true)
+            }
+            Ok(None) => Ok(false),
+            Err(e) => Err(format!(
+                "Failed to read tokens from {}: {}",
+                path.display(),
+                e
+            )),
+        }
+    }
+
+    /// Keeps the user's tokens fresh for as long as the server is up.
+    /// The handle goes into the AuthState so logging out can stop it.
+    pub fn spawn_refresh(self: &Arc<Self>) {
+        let client = self.clone();
+        self.user.set_refresher(task::spawn(async move {
+            loop {
+                let refresh_in = client
+                    .user
+                    .tokens()
+                    .map(|t| t.refresh_in())
+                    .unwrap_or(Duration::ZERO);
+                time::sleep(refresh_in).await;
+                if !client.refresh_tokens().await {
+                    // The token's probably still good for a few minutes, so just try again shortly.
+                    time::sleep(Duration::from_secs(30)).await;
+                }
+            }
+        }));
+    }
+
+    /// Writes the user's tokens to the configured store, if there is one and they've changed since it last saw them,
+    /// so a restart doesn't log us out.
+    /// Only ever updates the store: if it's gone, someone ran `obscurify logout`, and putting it back would undo that.
+    pub fn persist_tokens(&self) {
+        self.save_tokens(false)
+    }
+
+    /// persist_tokens, but allowed to create the store, for a fresh login.
+    fn save_tokens(&self, create: bool) {
+        // Made-up replay tokens would clobber the real ones.
+        if self.config.spotify.replaying() || !self.user.unsaved() {
+            return;
+        }
+        let (path, current) = match (&self.config.token_store, self.user.tokens()) {
+            (Some(path), Some(current)) => (path, current),
+            _ => return,
+        };
+        if !create && !path.exists() {
+            tracing::warn!(path = %path.display(), "Token store was deleted; not writing it back");
+            self.user.mark_saved();
+            return;
+        }
+        match store::save(path, &current) {
+            Ok(()) => self.user.mark_saved(),
+            Err(e) => tracing::error!(path = %path.display(), "Failed to write tokens: {}", e),
+        }
+    }
+
+    /// Trades the refresh token for a new set.
+    /// Returns whether it worked, so the refresher can retry instead of dying.
+    pub async fn refresh_tokens(&self) -> bool {
+        let refresh_token = match self.user.tokens().and_then(|t| t.refresh_token.clone()) {
+            Some(refresh_token) => refresh_token,
+            None => {
+                tracing::warn!("Can't refresh without a refresh token");
+                refreshed(&self.user, TokenKind::User, false);
+                return false;
+            }
+        };
+
+        let response = match self
+            .redeem_authorization_code_for_access_token(refresh_token.as_str(), true)
+            .await
+        {
+            Ok(response) => response,
+            Err(e) => {
+                tracing::warn!("Failed to refresh tokens: {}", e);
+                refreshed(&self.user, TokenKind::User, false);
+                return false;
+            }
+        };
+
+        if !response.status().is_success() {
+            tracing::warn!(
+                status = response.status().as_u16(),
+                "Failed to refresh tokens"
+            );
+            refreshed(&self.user, TokenKind::User, false);
+            return false;
+        }
+        let token_set = match response.json::<TokenSet>().await {
+            Ok(token_set) => token_set,
+            Err(e) => {
+                tracing::warn!("Failed to parse JSON of token refresh response: {}", e);
+                refreshed(&self.user, TokenKind::User, false);
+                return false;
+            }
+        };
+
+        self.config.warn_on_missing_scopes(&token_set);
+        self.user.swap(token_set);
+        self.persist_tokens();
+        refreshed(&self.user, TokenKind::User, true);
+        true
+    }
+
+    /// Grabs a fresh app-only token via the client-credentials flow.
+    /// There's no refresh token here; we just ask again whenever it's about to run out.
+    /// Returns whether it worked, so the caller can back off instead of hammering Spotify.
+    pub async fn refresh_app_token(&self) -> bool {
+        let _refreshing = self.app_refresh.lock().await;
+        self.fetch_app_token().await
+    }
+
+    /// For requests that find the app token missing or expired. If someone else is already
+    /// fetching one, waits for them and uses whatever they got instead of asking again.
+    pub async fn ensure_app_token(&self) -> bool {
+        let attempts = self.app_attempts.load(Ordering::SeqCst);
+        let _refreshing = self.app_refresh.lock().await;
+        if self.app_attempts.load(Ordering::SeqCst) == attempts
+            && self.app.tokens().is_none_or(|t| t.is_expired())
+        {
+            return self.fetch_app_token().await;
+        }
+        self.app.tokens().is_some_and(|t| !t.is_expired())
+    }
+
+    /// The actual trip to the token endpoint. Only call with app_refresh held.
+    async fn fetch_app_token(&self) -> bool {
+        self.app_attempts.fetch_add(1, Ordering::SeqCst);
+        let response = match self.get_client_credentials().await {
+            Ok(response) => response,
+            Err(e) => {
+                tracing::warn!("{}", e);
+                refreshed(&self.app, TokenKind::App, false);
+                return false;
+            }
+        };
+        if !response.status().is_success() {
+            tracing::warn!(
+                status = response.status().as_u16(),
+                "Failed to get a client-credentials token"
+            );
+            refreshed(&self.app, TokenKind::App, false);
+            return false;
+        }
+        match response.json::<TokenSet>().await {
+            Ok(token_set) => {
+                self.app.swap(token_set);
+                refreshed(&self.app, TokenKind::App, true);
+                true
+            }
+            Err(e) => {
+                tracing::warn!("Failed to parse client-credentials token response: {}", e);
+                refreshed(&self.app, TokenKind::App, false);
+                false
+            }
+        }
+    }
+}
+
+/// Notes how a refresh went, for /metrics and /readyz.
+fn refreshed(tokens: &AuthState, kind: TokenKind, succeeded: bool) {
+    metrics::refreshed(kind, succeeded);
+    tokens.record_refresh(succeeded);
+}
+
+fn with_trailing_slash(base: &str) -> String {
+    match base.ends_with('/') {
+        true => base.to_owned(),
+        false => format!("{}/", base),
+    }
 }
 
 fn build_client(h: Option<header::HeaderMap>) -> Result<reqwest::Client, reqwest::Error> {
@@ -179,20 +831,78 @@ fn build_client(h: Option<header::HeaderMap>) -> Result<reqwest::Client, reqwest
         })
         .build()
 }
+/// The player endpoints leave episodes out (item comes back null) unless they're asked for,
+/// so ask for them wherever the endpoint doesn't already say what it wants.
+pub fn with_additional_types(endpoint: String) -> String {
+    let (path, query) = endpoint.split_once('?').unwrap_or((&endpoint, ""));
+    let player = matches!(
+        path.trim_matches('/'),
+        "me/player" | "me/player/currently-playing"
+    );
+    if !player
+        || query
+            .split('&')
+            .any(|pair| pair.starts_with("additional_types="))
+    {
+        return endpoint;
+    }
+    match query.is_empty() {
+        true => format!("{}?{}", path, ADDITIONAL_TYPES),
+        false => format!("{}&{}", endpoint, ADDITIONAL_TYPES),
+    }
+}
+
+/// The method and scopes for a write endpoint, if it's one we allow.
+pub fn write_endpoint(endpoint: &str) -> Option<(Method, &'static [&'static str])> {
+    let path = endpoint
+        .split('?')
+        .next()
+        .unwrap_or_default()
+        .trim_matches('/');
+    WRITE_ENDPOINTS
+        .iter()
+        .find(|(allowed, _, _)| path == *allowed)
+        .map(|(_, method, scopes)| (method.clone(), *scopes))
+}
+
+/// Looks up the scopes needed to GET the given endpoint.
+/// Anything we don't know about (public catalog data, mostly) gets no scopes at all.
+pub fn scopes_for_endpoint(endpoint: &str) -> &'static [&'static str] {
+    let path = endpoint
+        .split('?')
+        .next()
+        .unwrap_or_default()
+        .trim_matches('/');
+    ENDPOINT_SCOPES
+        .iter()
+        .find(|(prefix, _)| {
+            path == *prefix
+                || path
+                    .strip_prefix(prefix)
+                    .is_some_and(|rest| rest.starts_with('/'))
+        })
+        .map_or(&[], |(_, scopes)| *scopes)
+}
+
 /// Retrieves an arbitrary JSON value from the given slice-encoded JSON tree.
 /// Returns an Option<Value> depending on whether or not the given value was in the tree.
 /// The Value will live as long as the input Value does.
+/// Numeric children index into arrays, so `items/0/id` works.
 pub fn retrieve_json_value<'a>(
     input: &'a Value,
     value_tree: &[&str],
 ) -> Option<&'a serde_json::Value> {
     let mut current_root: &Value = input;
     for child in value_tree {
-        match &current_root[child] {
-            Value::Null => {
+        let next = match current_root {
+            Value::Array(items) => child.parse::<usize>().ok().and_then(|i| items.get(i)),
+            other => other.get(child),
+        };
+        match next {
+            None | Some(Value::Null) => {
                 return None;
             }
-            other => {
+            Some(other) => {
                 current_root = other;
             }
         }
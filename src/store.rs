@@ -0,0 +1,80 @@
+use serde::{Deserialize, Serialize};
+
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
+
+use crate::authstate::TokenSet;
+
+/// What actually goes on disk.
+/// TokenSet deserializes from the token endpoint's shape (`expires_in`, space-separated `scope`),
+/// so it gets its own stable format here with an absolute expiry.
+#[derive(Serialize, Deserialize)]
+struct StoredTokens {
+    access_token: String,
+    refresh_token: Option<String>,
+    scopes: Vec<String>,
+    /// Seconds since the Unix epoch.
+    expires_at: u64,
+}
+
+impl From<&TokenSet> for StoredTokens {
+    fn from(t: &TokenSet) -> Self {
+        StoredTokens {
+            access_token: t.access_token.clone(),
+            refresh_token: t.refresh_token.clone(),
+            scopes: t.scopes.clone(),
+            expires_at: t
+                .expires_at
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        }
+    }
+}
+
+impl From<StoredTokens> for TokenSet {
+    fn from(t: StoredTokens) -> Self {
+        TokenSet {
+            access_token: t.access_token,
+            refresh_token: t.refresh_token,
+            scopes: t.scopes,
+            expires_at: UNIX_EPOCH + Duration::from_secs(t.expires_at),
+        }
+    }
+}
+
+/// Reads tokens back out of the store. A missing file just means nobody has logged in yet.
+pub fn load(path: &Path) -> io::Result<Option<TokenSet>> {
+    match fs::read_to_string(path) {
+        Ok(contents) => serde_json::from_str::<StoredTokens>(&contents)
+            .map(|stored| Some(stored.into()))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Writes tokens to the store.
+/// Goes through a temporary file and a rename so the server never reads half a file,
+/// and keeps the permissions tight since these are live credentials.
+pub fn save(path: &Path, tokens: &TokenSet) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(&tmp)?;
+    file.write_all(serde_json::to_string_pretty(&StoredTokens::from(tokens))?.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)
+}
+
+/// Throws the stored tokens away. Already being gone counts as success.
+pub fn delete(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,68 @@
+use sd_notify::NotifyState;
+
+use socket2::Socket;
+
+use std::io;
+use std::os::fd::{FromRawFd, OwnedFd};
+use std::os::unix::net::UnixListener;
+use std::time::Duration;
+
+use tokio::{task, time};
+
+use crate::serve::Listeners;
+
+/// Takes ownership of whatever sockets systemd passed in through `LISTEN_FDS`, sorted by scheme.
+/// systemd says which is which through `FileDescriptorName=` (http, https or unix);
+/// unnamed TCP sockets on port 443 count as https and the rest as http.
+/// Without socket activation this is just empty.
+pub fn inherited_listeners() -> io::Result<Listeners> {
+    let mut inherited = Listeners::default();
+    for (fd, name) in sd_notify::listen_fds_with_names(true)? {
+        // systemd gave these to us and nobody else in this process knows about them.
+        let socket = unsafe { Socket::from_raw_fd(fd) };
+        socket.set_nonblocking(true)?;
+        let local = socket.local_addr()?;
+        if local.is_unix() {
+            inherited.unix = Some(UnixListener::from(OwnedFd::from(socket)));
+            continue;
+        }
+        let port = match local.as_socket() {
+            Some(addr) => addr.port(),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Inherited socket {} isn't TCP or Unix", name),
+                ))
+            }
+        };
+        match name.as_str() {
+            "https" => inherited.https.push(socket.into()),
+            "http" => inherited.http.push(socket.into()),
+            _ if port == 443 => inherited.https.push(socket.into()),
+            _ => inherited.http.push(socket.into()),
+        }
+    }
+    Ok(inherited)
+}
+
+/// Tells systemd we're up, and starts petting the watchdog if the unit has one.
+/// Does nothing when we weren't started by systemd.
+pub fn ready() {
+    let _ = sd_notify::notify(false, &[NotifyState::Ready]);
+    let mut usec = 0;
+    if sd_notify::watchdog_enabled(false, &mut usec) {
+        // Half the timeout, as sd_watchdog_enabled(3) recommends.
+        let every = Duration::from_micros(usec) / 2;
+        task::spawn(async move {
+            let mut interval = time::interval(every);
+            loop {
+                interval.tick().await;
+                let _ = sd_notify::notify(false, &[NotifyState::Watchdog]);
+            }
+        });
+    }
+}
+
+pub fn stopping() {
+    let _ = sd_notify::notify(false, &[NotifyState::Stopping]);
+}
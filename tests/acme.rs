@@ -0,0 +1,98 @@
+//! Getting a certificate from an ACME CA (a mock one) and serving with it.
+
+mod common;
+
+use common::mock_acme::MockAcme;
+use common::mock_spotify::MockSpotify;
+use common::Obscurify;
+
+use reqwest::StatusCode;
+
+use std::path::Path;
+use std::time::Duration;
+
+/// Starts the server with ACME on, plain HTTP on `http_port` for the challenges.
+fn start(
+    mock: &MockSpotify,
+    acme: &MockAcme,
+    http_port: u16,
+    https_port: u16,
+    cache: &Path,
+) -> Obscurify {
+    Obscurify::spawn(
+        http_port,
+        &format!(
+            "api_base: {}\naccounts_base: {}\n",
+            mock.api_base(),
+            mock.accounts_base()
+        ),
+        &format!(
+            "[routing]\n\
+             https: 127.0.0.1:{}\n\
+             \n\
+             [acme]\n\
+             domains: localhost\n\
+             directory: {}\n\
+             cache: {}\n",
+            https_port,
+            acme.directory(),
+            cache.display()
+        ),
+    )
+}
+
+/// Waits for the HTTPS side to come up with a certificate the mock CA vouches for.
+async fn wait_for_https(acme: &MockAcme, https_port: u16) {
+    let http = reqwest::Client::builder()
+        .add_root_certificate(reqwest::Certificate::from_pem(acme.ca_pem().as_bytes()).unwrap())
+        .build()
+        .unwrap();
+    for _ in 0..100 {
+        if let Ok(response) = http
+            .get(format!("https://localhost:{}/healthz", https_port))
+            .send()
+            .await
+        {
+            assert_eq!(response.status(), StatusCode::OK);
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("HTTPS never came up on {}", https_port);
+}
+
+#[tokio::test]
+async fn issues_a_certificate_and_serves_it() {
+    let mock = MockSpotify::start().await;
+    let http_port = common::free_port();
+    let acme = MockAcme::start(http_port).await;
+    let cache = common::scratch_dir();
+
+    let https_port = common::free_port();
+    let server = start(&mock, &acme, http_port, https_port, &cache);
+    wait_for_https(&acme, https_port).await;
+    assert_eq!(acme.orders(), 1);
+    // The stale nonce got retried rather than failing the order.
+    assert_eq!(acme.bad_nonces(), 1);
+    assert!(cache.join("cert.pem").exists() && cache.join("key.pem").exists());
+    assert!(!cache.join("cert.tmp").exists() && !cache.join("key.tmp").exists());
+    drop(server);
+
+    // A key that doesn't go with the certificate (a crash between writing them, say) gets a new pair,
+    // not a server that can't finish a handshake.
+    std::fs::write(
+        cache.join("key.pem"),
+        rcgen::KeyPair::generate(&rcgen::PKCS_ECDSA_P256_SHA256)
+            .unwrap()
+            .serialize_pem(),
+    )
+    .unwrap();
+    let http_port = common::free_port();
+    acme.validate_on(http_port);
+    let https_port = common::free_port();
+    let _server = start(&mock, &acme, http_port, https_port, &cache);
+    wait_for_https(&acme, https_port).await;
+    assert_eq!(acme.orders(), 2);
+
+    let _ = std::fs::remove_dir_all(&cache);
+}
@@ -0,0 +1,258 @@
+//! Write services: only what's allowed, only as often as allowed, and all of it written down.
+
+mod common;
+
+use common::mock_spotify::{MockSpotify, CAPTCHA_SECRET, GOOD_CAPTCHA, TRACK_ID};
+use common::Obscurify;
+
+use obscurify::audit::MAX_VALUE;
+
+use reqwest::{header, StatusCode};
+
+use serde_json::Value;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UnixStream;
+
+fn queue(extra: &str) -> String {
+    format!(
+        "[service.queue]\n\
+         domain: /queue\n\
+         target: api\n\
+         endpoint: me/player/queue\n\
+         method: post\n\
+         query.uri: re:spotify:track:[A-Za-z0-9]{{22}}\n\
+         {}\n\
+         [audit]\n\
+         path: audit.jsonl\n",
+        extra
+    )
+}
+
+fn post(server: &Obscurify, uri: &str) -> reqwest::RequestBuilder {
+    server
+        .http
+        .post(format!("{}/queue", server.base))
+        .query(&[("uri", uri)])
+}
+
+#[tokio::test]
+async fn queues_valid_tracks_and_nothing_else() {
+    let mock = MockSpotify::start().await;
+    let server = Obscurify::start(&mock, &queue("")).await;
+    server.authorize().await;
+    let track = format!("spotify:track:{}", TRACK_ID);
+
+    let queued = post(&server, &track).send().await.unwrap();
+    assert_eq!(queued.status(), StatusCode::NO_CONTENT);
+    assert_eq!(mock.queued(), vec![track.clone()]);
+
+    for bad in [
+        "spotify:track:short",
+        "spotify:album:4uLU6hMCjMI75M1A2tKUQC",
+        "spotify:track:4uLU6hMCjMI75M1A2tKUQC&device_id=x",
+    ] {
+        let response = post(&server, bad).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", bad);
+    }
+    let missing = server
+        .http
+        .post(format!("{}/queue", server.base))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(missing.status(), StatusCode::BAD_REQUEST);
+    // Writes are POST only; a stray GET (a crawler following a link, say) doesn't do anything.
+    let got = server.get(&format!("/queue?uri={}", track)).await.unwrap();
+    assert_eq!(got.status(), StatusCode::METHOD_NOT_ALLOWED);
+    assert_eq!(mock.queued().len(), 1);
+
+    let preflight = server
+        .http
+        .request(reqwest::Method::OPTIONS, format!("{}/queue", server.base))
+        .header(header::ORIGIN, "https://example.com")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(
+        preflight.headers()[header::ACCESS_CONTROL_ALLOW_METHODS],
+        "POST, OPTIONS"
+    );
+
+    let audit = std::fs::read_to_string(server.dir().join("audit.jsonl")).unwrap();
+    let outcomes: Vec<String> = audit
+        .lines()
+        .map(|line| serde_json::from_str::<Value>(line).unwrap())
+        .map(|entry| entry["outcome"].as_str().unwrap().to_owned())
+        .collect();
+    assert_eq!(
+        outcomes,
+        ["sent", "rejected", "rejected", "rejected", "rejected"]
+    );
+}
+
+#[tokio::test]
+async fn rate_limits_each_visitor() {
+    let mock = MockSpotify::start().await;
+    let server = Obscurify::start(&mock, &queue("rate_limit: 2/3600")).await;
+    server.authorize().await;
+    let track = format!("spotify:track:{}", TRACK_ID);
+
+    for _ in 0..2 {
+        let queued = post(&server, &track).send().await.unwrap();
+        assert_eq!(queued.status(), StatusCode::NO_CONTENT);
+    }
+    let limited = post(&server, &track).send().await.unwrap();
+    assert_eq!(limited.status(), StatusCode::TOO_MANY_REQUESTS);
+    let retry_after: u64 = limited.headers()[header::RETRY_AFTER]
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!(retry_after > 3500 && retry_after <= 3600);
+    assert_eq!(mock.queued().len(), 2);
+}
+
+#[tokio::test]
+async fn counts_an_ipv6_visitor_by_their_64() {
+    let mock = MockSpotify::start().await;
+    let server = Obscurify::start(
+        &mock,
+        &queue("rate_limit: 2/3600\n[routing]\ntrusted_proxies: 127.0.0.1"),
+    )
+    .await;
+    server.authorize().await;
+    let track = format!("spotify:track:{}", TRACK_ID);
+    let from = |ip: &str| post(&server, &track).header("x-forwarded-for", ip);
+
+    for ip in ["2001:db8::1", "2001:db8::2"] {
+        let queued = from(ip).send().await.unwrap();
+        assert_eq!(queued.status(), StatusCode::NO_CONTENT, "{}", ip);
+    }
+    // Same /64, new address: still the same visitor.
+    let limited = from("2001:db8::ffff:3").send().await.unwrap();
+    assert_eq!(limited.status(), StatusCode::TOO_MANY_REQUESTS);
+    let elsewhere = from("2001:db8:0:1::1").send().await.unwrap();
+    assert_eq!(elsewhere.status(), StatusCode::NO_CONTENT);
+    assert_eq!(mock.queued().len(), 3);
+}
+
+#[tokio::test]
+async fn turns_away_writes_from_nobody_in_particular() {
+    let mock = MockSpotify::start().await;
+    let dir = common::scratch_dir();
+    let path = dir.join("obscurify.sock");
+    let server = Obscurify::start(
+        &mock,
+        &queue(&format!("[routing]\nunix: {}", path.display())),
+    )
+    .await;
+    server.authorize().await;
+
+    // A proxy on the socket that doesn't say who it's passing along.
+    let mut socket = UnixStream::connect(&path).await.unwrap();
+    let request = format!(
+        "POST /queue?uri=spotify:track:{} HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        TRACK_ID
+    );
+    socket.write_all(request.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    socket.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 403"), "{}", response);
+    assert!(mock.queued().is_empty());
+    drop(server);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn audits_only_what_the_service_takes() {
+    let mock = MockSpotify::start().await;
+    let server = Obscurify::start(&mock, &queue("")).await;
+    server.authorize().await;
+
+    let long = format!("spotify:track:{}", "x".repeat(10_000));
+    let response = post(&server, &long)
+        .query(&[("device_id", "secret-device")])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let audit = std::fs::read_to_string(server.dir().join("audit.jsonl")).unwrap();
+    let entry: Value = serde_json::from_str(audit.lines().last().unwrap()).unwrap();
+    let params = entry["params"].as_object().unwrap();
+    assert_eq!(params.keys().collect::<Vec<_>>(), ["uri"]);
+    assert_eq!(params["uri"].as_str().unwrap().len(), MAX_VALUE);
+}
+
+#[tokio::test]
+async fn an_unreachable_api_is_an_audited_502() {
+    let mock = MockSpotify::start().await;
+    let spotify = format!(
+        "api_base: {}\naccounts_base: {}\n",
+        common::dead_base(),
+        mock.accounts_base()
+    );
+    let server = Obscurify::start_with_spotify(&spotify, &queue("")).await;
+    server.authorize().await;
+
+    let response = post(&server, &format!("spotify:track:{}", TRACK_ID))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+    let audit = std::fs::read_to_string(server.dir().join("audit.jsonl")).unwrap();
+    let entry: Value = serde_json::from_str(audit.lines().last().unwrap()).unwrap();
+    assert_eq!(entry["outcome"], "upstream_error");
+    assert_eq!(entry["status"], 502);
+}
+
+#[test]
+fn an_unopenable_audit_log_fails_the_config() {
+    let dead = common::dead_base();
+    let config = common::load_config(&dead, &dead, "[audit]\npath: /nonexistent/audit.jsonl\n");
+    assert!(config.err().unwrap().contains("audit log"));
+}
+
+#[tokio::test]
+async fn gates_writes_behind_a_secret_and_a_captcha() {
+    let mock = MockSpotify::start().await;
+    let gates = format!(
+        "secret: hunter2\ncaptcha_verify: {}\ncaptcha_secret: {}",
+        mock.siteverify(),
+        CAPTCHA_SECRET
+    );
+    let server = Obscurify::start(&mock, &queue(&gates)).await;
+    server.authorize().await;
+    let track = format!("spotify:track:{}", TRACK_ID);
+
+    let anonymous = post(&server, &track)
+        .header("x-captcha-token", GOOD_CAPTCHA)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(anonymous.status(), StatusCode::UNAUTHORIZED);
+    let no_captcha = post(&server, &track)
+        .bearer_auth("hunter2")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(no_captcha.status(), StatusCode::FORBIDDEN);
+    let bad_captcha = post(&server, &track)
+        .bearer_auth("hunter2")
+        .header("x-captcha-token", "mock-bad-captcha")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(bad_captcha.status(), StatusCode::FORBIDDEN);
+    assert!(mock.queued().is_empty());
+
+    let queued = post(&server, &track)
+        .bearer_auth("hunter2")
+        .header("x-captcha-token", GOOD_CAPTCHA)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(queued.status(), StatusCode::NO_CONTENT);
+    assert_eq!(mock.queued(), vec![track]);
+}
@@ -0,0 +1,80 @@
+//! `obscurify auth` from a terminal while the server's already up.
+
+mod common;
+
+use common::mock_spotify::{MockSpotify, TRACK_ID};
+use common::Obscurify;
+
+use reqwest::{redirect, StatusCode};
+
+use std::io::{BufRead, BufReader};
+use std::process::Stdio;
+
+const STORE: &str = "[tokens]\nstore: tokens.json\n";
+
+/// Runs `obscurify auth` with its redirect on a port of its own (the server has the usual one),
+/// and clicks through the URL it prints.
+async fn auth(server: &Obscurify) -> std::process::Output {
+    let conf = std::fs::read_to_string(server.dir().join("obsc.conf")).unwrap();
+    let server_port = server.base.rsplit(':').next().unwrap();
+    let cli_conf = conf.replace(
+        &format!("redirect: http://127.0.0.1:{}/", server_port),
+        &format!("redirect: http://127.0.0.1:{}/", common::free_port()),
+    );
+    std::fs::write(server.dir().join("cli.conf"), cli_conf).unwrap();
+
+    let mut cli = server
+        .command("auth", "cli.conf")
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut lines = BufReader::new(cli.stdout.take().unwrap()).lines();
+    let url = lines
+        .by_ref()
+        .map(Result::unwrap)
+        .find(|line| line.starts_with("http"))
+        .expect("No URL to open");
+
+    let http = reqwest::Client::builder()
+        .redirect(redirect::Policy::none())
+        .build()
+        .unwrap();
+    let to_cli = http.get(&url).send().await.unwrap();
+    let caught = http.get(common::location(&to_cli)).send().await.unwrap();
+    assert_eq!(caught.status(), StatusCode::OK);
+
+    // The CLI redeems the code against the mock, which lives on this thread; don't block it.
+    tokio::task::spawn_blocking(move || {
+        let rest: Vec<String> = lines.map(Result::unwrap).collect();
+        let mut output = cli.wait_with_output().unwrap();
+        output.stdout = rest.join("\n").into_bytes();
+        output
+    })
+    .await
+    .unwrap()
+}
+
+#[tokio::test]
+async fn hands_new_tokens_to_a_running_server() {
+    let mock = MockSpotify::start().await;
+    let server = Obscurify::start(&mock, &format!("{}[admin]\nsecret: hunter2\n", STORE)).await;
+    let before = server.get("/current_track").await.unwrap();
+    assert_eq!(before.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+    let output = auth(&server).await;
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("picked them up"));
+    let track = server.get("/current_track").await.unwrap();
+    assert_eq!(track.text().await.unwrap(), TRACK_ID);
+}
+
+#[tokio::test]
+async fn wont_go_behind_a_running_servers_back() {
+    let mock = MockSpotify::start().await;
+    let server = Obscurify::start(&mock, STORE).await;
+
+    let output = server.run("auth");
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("is running"));
+    assert!(!server.dir().join("tokens.json").exists());
+}
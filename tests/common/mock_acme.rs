@@ -0,0 +1,354 @@
+use axum::{
+    extract::State,
+    http::{StatusCode, Uri},
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+
+use parking_lot::Mutex;
+
+use rcgen::{
+    BasicConstraints, Certificate, CertificateParams, DnType, IsCa, KeyPair, RemoteKeyPair,
+    SignatureAlgorithm, PKCS_ECDSA_P256_SHA256,
+};
+
+use ring::digest::{digest, SHA256};
+use ring::signature::{UnparsedPublicKey, ECDSA_P256_SHA256_FIXED};
+
+use serde_json::{json, Value};
+
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use tokio::net::TcpListener;
+
+use x509_parser::prelude::FromDer;
+
+struct AcmeState {
+    base: String,
+    /// Where the server under test answers HTTP-01 challenges.
+    http_port: u16,
+    ca: Certificate,
+    /// Handed out and not used yet. Each one's good for a single request.
+    nonces: HashSet<String>,
+    next_nonce: usize,
+    /// The account key, from the new-account request.
+    jwk: Option<Value>,
+    /// Whether we've made the client retry a stale nonce yet.
+    sent_bad_nonce: bool,
+    bad_nonces: usize,
+    orders: usize,
+    domains: Vec<String>,
+    token: String,
+    authz_status: &'static str,
+    chain: Option<String>,
+}
+
+/// A tiny ACME CA for one account and one order at a time: checks every JWS, hands out single-use
+/// nonces (and turns the first new-order away with badNonce), really fetches the HTTP-01 challenge,
+/// and signs whatever key the CSR comes with.
+pub struct MockAcme {
+    pub addr: SocketAddr,
+    state: Arc<Mutex<AcmeState>>,
+}
+
+impl MockAcme {
+    pub async fn start(http_port: u16) -> MockAcme {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut params = CertificateParams::new(Vec::new());
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params
+            .distinguished_name
+            .push(DnType::CommonName, "Mock ACME CA");
+        let state = Arc::new(Mutex::new(AcmeState {
+            base: format!("http://{}", addr),
+            http_port,
+            ca: Certificate::from_params(params).unwrap(),
+            nonces: HashSet::new(),
+            next_nonce: 0,
+            jwk: None,
+            sent_bad_nonce: false,
+            bad_nonces: 0,
+            orders: 0,
+            domains: Vec::new(),
+            token: String::new(),
+            authz_status: "pending",
+            chain: None,
+        }));
+        let app = Router::new()
+            .route("/directory", get(directory))
+            .route("/nonce", get(nonce))
+            .fallback(signed)
+            .with_state(state.clone());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        MockAcme { addr, state }
+    }
+
+    pub fn directory(&self) -> String {
+        format!("http://{}/directory", self.addr)
+    }
+
+    /// The CA certificate everything gets issued under, for trusting what the server ends up serving.
+    pub fn ca_pem(&self) -> String {
+        self.state.lock().ca.serialize_pem().unwrap()
+    }
+
+    /// Points challenge validation at a different server.
+    pub fn validate_on(&self, http_port: u16) {
+        self.state.lock().http_port = http_port;
+    }
+
+    pub fn orders(&self) -> usize {
+        self.state.lock().orders
+    }
+
+    /// How many requests got turned away for a stale nonce.
+    pub fn bad_nonces(&self) -> usize {
+        self.state.lock().bad_nonces
+    }
+}
+
+async fn directory(State(state): State<Arc<Mutex<AcmeState>>>) -> Response {
+    let base = state.lock().base.clone();
+    Json(json!({
+        "newNonce": format!("{}/nonce", base),
+        "newAccount": format!("{}/account", base),
+        "newOrder": format!("{}/order", base),
+    }))
+    .into_response()
+}
+
+async fn nonce(State(state): State<Arc<Mutex<AcmeState>>>) -> Response {
+    let nonce = fresh_nonce(&mut state.lock());
+    (StatusCode::OK, [("replay-nonce", nonce)]).into_response()
+}
+
+fn fresh_nonce(state: &mut AcmeState) -> String {
+    state.next_nonce += 1;
+    let nonce = format!("nonce{}", state.next_nonce);
+    state.nonces.insert(nonce.clone());
+    nonce
+}
+
+fn problem(state: &mut AcmeState, status: StatusCode, kind: &str) -> Response {
+    let nonce = fresh_nonce(state);
+    (
+        status,
+        [("replay-nonce", nonce)],
+        Json(json!({"type": format!("urn:ietf:params:acme:error:{}", kind)})),
+    )
+        .into_response()
+}
+
+/// Every JWS-signed POST, sorted out by path once the signature checks out.
+async fn signed(State(state): State<Arc<Mutex<AcmeState>>>, uri: Uri, body: String) -> Response {
+    let (path, payload, validate) = {
+        let mut state = state.lock();
+        let (path, payload) = match verify(&mut state, &uri, &body) {
+            Ok(verified) => verified,
+            Err(kind) => return problem(&mut state, StatusCode::BAD_REQUEST, kind),
+        };
+        if path == "/order" && !state.sent_bad_nonce {
+            state.sent_bad_nonce = true;
+            state.bad_nonces += 1;
+            return problem(&mut state, StatusCode::BAD_REQUEST, "badNonce");
+        }
+        let validate = (path == "/chall/1").then(|| {
+            let thumbprint = digest(&SHA256, state.jwk.as_ref().unwrap().to_string().as_bytes());
+            (
+                format!(
+                    "http://127.0.0.1:{}/.well-known/acme-challenge/{}",
+                    state.http_port, state.token
+                ),
+                format!("{}.{}", state.token, URL_SAFE_NO_PAD.encode(thumbprint)),
+            )
+        });
+        (path, payload, validate)
+    };
+    // The one place we go out to the server under test, so no lock held.
+    let validated = match validate {
+        Some((url, expected)) => match reqwest::get(url).await {
+            Ok(response) => response.text().await.ok() == Some(expected),
+            Err(_) => false,
+        },
+        None => false,
+    };
+
+    let mut state = state.lock();
+    let nonce = fresh_nonce(&mut state);
+    let base = state.base.clone();
+    let order = |state: &AcmeState| {
+        json!({
+            "status": if state.chain.is_some() { "valid" } else { "pending" },
+            "authorizations": [format!("{}/authz/1", base)],
+            "finalize": format!("{}/finalize/1", base),
+            "certificate": state.chain.as_ref().map(|_| format!("{}/cert/1", base)),
+        })
+    };
+    let body = match path.as_str() {
+        "/account" => {
+            return (
+                StatusCode::CREATED,
+                [
+                    ("replay-nonce", nonce),
+                    ("location", format!("{}/account/1", base)),
+                ],
+                Json(json!({"status": "valid"})),
+            )
+                .into_response()
+        }
+        "/order" => {
+            state.orders += 1;
+            state.domains = payload["identifiers"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|id| id["value"].as_str().unwrap().to_owned())
+                .collect();
+            state.token = format!("token{}", state.orders);
+            state.authz_status = "pending";
+            state.chain = None;
+            return (
+                StatusCode::CREATED,
+                [
+                    ("replay-nonce", nonce),
+                    ("location", format!("{}/order/1", base)),
+                ],
+                Json(order(&state)),
+            )
+                .into_response();
+        }
+        "/order/1" => order(&state),
+        "/authz/1" => json!({
+            "status": state.authz_status,
+            "challenges": [{
+                "type": "http-01",
+                "url": format!("{}/chall/1", base),
+                "token": state.token,
+            }],
+        }),
+        "/chall/1" => {
+            state.authz_status = if validated { "valid" } else { "invalid" };
+            json!({"status": state.authz_status})
+        }
+        "/finalize/1" if state.authz_status == "valid" => {
+            let csr = URL_SAFE_NO_PAD
+                .decode(payload["csr"].as_str().unwrap())
+                .unwrap();
+            state.chain = Some(issue(&state, &csr));
+            order(&state)
+        }
+        "/cert/1" if state.chain.is_some() => {
+            return (
+                [
+                    ("replay-nonce", nonce),
+                    (
+                        "content-type",
+                        String::from("application/pem-certificate-chain"),
+                    ),
+                ],
+                state.chain.clone().unwrap(),
+            )
+                .into_response()
+        }
+        _ => return problem(&mut state, StatusCode::FORBIDDEN, "unauthorized"),
+    };
+    ([("replay-nonce", nonce)], Json(body)).into_response()
+}
+
+/// Checks the JWS the way a CA would: a nonce we handed out, the URL it was actually sent to,
+/// and a signature from the account key. Hands back the path and the decoded payload,
+/// or what kind of problem to answer with.
+fn verify(state: &mut AcmeState, uri: &Uri, body: &str) -> Result<(String, Value), &'static str> {
+    let jws: Value = serde_json::from_str(body).map_err(|_| "malformed")?;
+    let decode = |field: &str| {
+        URL_SAFE_NO_PAD
+            .decode(jws[field].as_str().unwrap_or_default())
+            .ok()
+    };
+    let protected: Value = decode("protected")
+        .and_then(|p| serde_json::from_slice(&p).ok())
+        .ok_or("malformed")?;
+    if !state
+        .nonces
+        .remove(protected["nonce"].as_str().unwrap_or_default())
+    {
+        return Err("badNonce");
+    }
+    if protected["url"] != format!("{}{}", state.base, uri.path()) || protected["alg"] != "ES256" {
+        return Err("malformed");
+    }
+    let jwk = match (&protected["jwk"], &protected["kid"]) {
+        (Value::Object(_), Value::Null) if uri.path() == "/account" => {
+            state.jwk = Some(protected["jwk"].clone());
+            protected["jwk"].clone()
+        }
+        (Value::Null, Value::String(_)) if state.jwk.is_some() => state.jwk.clone().unwrap(),
+        _ => return Err("malformed"),
+    };
+    let coordinate = |c: &str| {
+        URL_SAFE_NO_PAD
+            .decode(jwk[c].as_str().unwrap_or_default())
+            .unwrap_or_default()
+    };
+    let point = [vec![4], coordinate("x"), coordinate("y")].concat();
+    let signed = format!(
+        "{}.{}",
+        jws["protected"].as_str().unwrap_or_default(),
+        jws["payload"].as_str().unwrap_or_default()
+    );
+    let signature = decode("signature").unwrap_or_default();
+    if UnparsedPublicKey::new(&ECDSA_P256_SHA256_FIXED, point)
+        .verify(signed.as_bytes(), &signature)
+        .is_err()
+    {
+        return Err("malformed");
+    }
+    // POST-as-GET has an empty payload.
+    let payload = decode("payload")
+        .and_then(|p| serde_json::from_slice(&p).ok())
+        .unwrap_or(Value::Null);
+    Ok((uri.path().to_owned(), payload))
+}
+
+/// The CSR's public key, for a certificate we sign with the CA rather than the (absent) private key.
+struct CsrKey(Vec<u8>);
+
+impl RemoteKeyPair for CsrKey {
+    fn public_key(&self) -> &[u8] {
+        &self.0
+    }
+
+    fn sign(&self, _: &[u8]) -> Result<Vec<u8>, rcgen::Error> {
+        Err(rcgen::Error::RemoteKeyError)
+    }
+
+    fn algorithm(&self) -> &'static SignatureAlgorithm {
+        &PKCS_ECDSA_P256_SHA256
+    }
+}
+
+fn issue(state: &AcmeState, csr: &[u8]) -> String {
+    let (_, request) =
+        x509_parser::certification_request::X509CertificationRequest::from_der(csr).unwrap();
+    let public_key = request
+        .certification_request_info
+        .subject_pki
+        .subject_public_key
+        .data
+        .to_vec();
+    let mut params = CertificateParams::new(state.domains.clone());
+    params.alg = &PKCS_ECDSA_P256_SHA256;
+    params.key_pair = Some(KeyPair::from_remote(Box::new(CsrKey(public_key))).unwrap());
+    let leaf = Certificate::from_params(params).unwrap();
+    format!(
+        "{}{}",
+        leaf.serialize_pem_with_signer(&state.ca).unwrap(),
+        state.ca.serialize_pem().unwrap()
+    )
+}
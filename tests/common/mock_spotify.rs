@@ -0,0 +1,354 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, StatusCode, Uri},
+    response::{IntoResponse, Redirect, Response},
+    routing::{get, post},
+    Form, Json, Router,
+};
+
+use base64::Engine;
+
+use parking_lot::Mutex;
+
+use serde_json::{json, Value};
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use tokio::net::TcpListener;
+
+pub const CLIENT_ID: &str = "mock-client";
+pub const CLIENT_SECRET: &str = "mock-secret";
+pub const CODE: &str = "mock-authorization-code";
+pub const USER_TOKEN: &str = "mock-user-token";
+pub const REFRESH_TOKEN: &str = "mock-refresh-token";
+pub const APP_TOKEN: &str = "mock-app-token";
+pub const TRACK_ID: &str = "4uLU6hMCjMI75M1A2tKUQC";
+pub const EPISODE_ID: &str = "512ojhOuo1ktJprKbVcKyQ";
+pub const CAPTCHA_SECRET: &str = "mock-captcha-secret";
+/// The only CAPTCHA token /siteverify will vouch for.
+pub const GOOD_CAPTCHA: &str = "mock-good-captcha";
+
+/// What /me/player/currently-playing answers with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Playing {
+    Track,
+    /// Only shows up as an episode if it was asked for with additional_types, like the real thing.
+    Episode,
+    Ad,
+    Nothing,
+    Unauthorized,
+    RateLimited,
+    /// A 200 that isn't JSON at all, like a captive portal's login page.
+    Garbage,
+}
+
+struct MockState {
+    playing: Playing,
+    /// Every grant_type the token endpoint has seen, in order.
+    grants: Vec<String>,
+    playlists: usize,
+    /// Every uri that's been queued, in order.
+    queued: Vec<String>,
+}
+
+/// Just enough of accounts.spotify.com and api.spotify.com to run the whole flow offline.
+pub struct MockSpotify {
+    pub addr: SocketAddr,
+    state: Arc<Mutex<MockState>>,
+}
+
+impl MockSpotify {
+    pub async fn start() -> MockSpotify {
+        let state = Arc::new(Mutex::new(MockState {
+            playing: Playing::Track,
+            grants: Vec::new(),
+            playlists: 0,
+            queued: Vec::new(),
+        }));
+        let app = Router::new()
+            .route("/authorize", get(authorize))
+            .route("/api/token", post(token))
+            .route("/v1/me/player/currently-playing", get(currently_playing))
+            .route("/v1/me/top/tracks", get(top_tracks))
+            .route("/v1/playlists/:id", get(playlist))
+            .route("/v1/playlists/:id/tracks", get(playlist_tracks))
+            .route("/v1/me/player/queue", post(queue))
+            .route("/siteverify", post(siteverify))
+            .with_state(state.clone());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        MockSpotify { addr, state }
+    }
+
+    pub fn api_base(&self) -> String {
+        format!("http://{}/v1/", self.addr)
+    }
+
+    pub fn accounts_base(&self) -> String {
+        format!("http://{}/", self.addr)
+    }
+
+    pub fn set_playing(&self, playing: Playing) {
+        self.state.lock().playing = playing;
+    }
+
+    pub fn grants(&self) -> Vec<String> {
+        self.state.lock().grants.clone()
+    }
+
+    pub fn queued(&self) -> Vec<String> {
+        self.state.lock().queued.clone()
+    }
+
+    /// Where a write service's CAPTCHA gets checked.
+    pub fn siteverify(&self) -> String {
+        format!("http://{}/siteverify", self.addr)
+    }
+
+    /// How many times a playlist's been asked for.
+    pub fn playlists_served(&self) -> usize {
+        self.state.lock().playlists
+    }
+}
+
+/// Skips the login page and consent screen and goes straight back with a code.
+async fn authorize(Query(params): Query<HashMap<String, String>>) -> Response {
+    if params.get("client_id").map(String::as_str) != Some(CLIENT_ID)
+        || params.get("response_type").map(String::as_str) != Some("code")
+    {
+        return (StatusCode::BAD_REQUEST, "bad authorize request").into_response();
+    }
+    let redirect = match params.get("redirect_uri") {
+        Some(redirect) => redirect,
+        None => return (StatusCode::BAD_REQUEST, "no redirect_uri").into_response(),
+    };
+    let mut location = format!("{}?code={}", redirect, CODE);
+    if let Some(state) = params.get("state") {
+        location.push_str(&format!("&state={}", state));
+    }
+    Redirect::to(&location).into_response()
+}
+
+async fn token(
+    State(state): State<Arc<Mutex<MockState>>>,
+    headers: HeaderMap,
+    Form(params): Form<HashMap<String, String>>,
+) -> Response {
+    let expected = format!(
+        "Basic {}",
+        base64::engine::general_purpose::STANDARD
+            .encode(format!("{}:{}", CLIENT_ID, CLIENT_SECRET))
+    );
+    if headers
+        .get(header::AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        != Some(&expected)
+    {
+        return (
+            StatusCode::UNAUTHORIZED,
+            Json(json!({"error": "invalid_client"})),
+        )
+            .into_response();
+    }
+    let grant = params.get("grant_type").cloned().unwrap_or_default();
+    state.lock().grants.push(grant.clone());
+    match grant.as_str() {
+        "authorization_code" if params.get("code").map(String::as_str) == Some(CODE) => {
+            Json(json!({
+                "access_token": USER_TOKEN,
+                "token_type": "Bearer",
+                "scope": "user-read-currently-playing",
+                "expires_in": 3600,
+                "refresh_token": REFRESH_TOKEN,
+            }))
+            .into_response()
+        }
+        "refresh_token"
+            if params.get("refresh_token").map(String::as_str) == Some(REFRESH_TOKEN) =>
+        {
+            Json(json!({
+                "access_token": USER_TOKEN,
+                "token_type": "Bearer",
+                "scope": "user-read-currently-playing",
+                "expires_in": 3600,
+            }))
+            .into_response()
+        }
+        "client_credentials" => Json(json!({
+            "access_token": APP_TOKEN,
+            "token_type": "Bearer",
+            "expires_in": 3600,
+        }))
+        .into_response(),
+        _ => (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "invalid_grant"})),
+        )
+            .into_response(),
+    }
+}
+
+async fn currently_playing(
+    State(state): State<Arc<Mutex<MockState>>>,
+    Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+) -> Response {
+    let bearer = format!("Bearer {}", USER_TOKEN);
+    if headers
+        .get(header::AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        != Some(&bearer)
+    {
+        return unauthorized();
+    }
+    let playing = state.lock().playing;
+    match playing {
+        Playing::Track => Json(json!({
+            "is_playing": true,
+            "currently_playing_type": "track",
+            "item": {
+                "id": TRACK_ID,
+                "name": "Mock Track",
+                "type": "track",
+                "duration_ms": 215000,
+            },
+        }))
+        .into_response(),
+        Playing::Episode => {
+            let asked = params
+                .get("additional_types")
+                .is_some_and(|types| types.split(',').any(|t| t == "episode"));
+            Json(json!({
+                "is_playing": true,
+                "currently_playing_type": "episode",
+                "item": if asked {
+                    json!({
+                        "id": EPISODE_ID,
+                        "name": "Mock Episode",
+                        "type": "episode",
+                        "duration_ms": 3600000,
+                    })
+                } else {
+                    Value::Null
+                },
+            }))
+            .into_response()
+        }
+        Playing::Ad => Json(json!({
+            "is_playing": true,
+            "currently_playing_type": "ad",
+            "item": null,
+        }))
+        .into_response(),
+        Playing::Nothing => StatusCode::NO_CONTENT.into_response(),
+        Playing::Unauthorized => unauthorized(),
+        Playing::RateLimited => (
+            StatusCode::TOO_MANY_REQUESTS,
+            [(header::RETRY_AFTER, "30")],
+            Json(json!({"error": {"status": 429, "message": "API rate limit exceeded"}})),
+        )
+            .into_response(),
+        Playing::Garbage => "<html>Please log in to the Wi-Fi</html>".into_response(),
+    }
+}
+
+/// Echoes the query back as the first track's id, so tests can see exactly what was forwarded.
+async fn top_tracks(uri: Uri) -> Response {
+    Json(json!({
+        "items": [{"id": uri.query().unwrap_or_default(), "name": "Mock Track", "type": "track"}],
+    }))
+    .into_response()
+}
+
+/// Any playlist you like, named after its id. Counts how many it's handed out.
+async fn playlist(State(state): State<Arc<Mutex<MockState>>>, Path(id): Path<String>) -> Response {
+    state.lock().playlists += 1;
+    Json(json!({"id": id, "name": format!("Playlist {}", id)})).into_response()
+}
+
+/// Seven tracks, three to a page unless asked otherwise, with `next` links like the real thing.
+/// The "elsewhere" playlist's links point off somewhere we shouldn't be sending tokens;
+/// "circular" links from its last page back to the second, and "endless" keeps linking to empty pages past the end.
+async fn playlist_tracks(
+    Path(id): Path<String>,
+    Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+) -> Response {
+    const TOTAL: usize = 7;
+    let offset: usize = params
+        .get("offset")
+        .and_then(|o| o.parse().ok())
+        .unwrap_or(0);
+    let limit: usize = params
+        .get("limit")
+        .and_then(|l| l.parse().ok())
+        .unwrap_or(3);
+    let host = headers[header::HOST].to_str().unwrap();
+    let base = match id.as_str() {
+        "elsewhere" => String::from("http://elsewhere.invalid"),
+        _ => format!("http://{}", host),
+    };
+    let items: Vec<_> = (offset..TOTAL.min(offset + limit))
+        .map(|i| json!({"track": {"id": format!("track{}", i), "name": "Mock Track", "type": "track"}}))
+        .collect();
+    let next_offset = match id.as_str() {
+        "circular" if offset + limit >= TOTAL => Some(limit),
+        "endless" => Some(offset + limit),
+        _ => (offset + limit < TOTAL).then_some(offset + limit),
+    };
+    let next = next_offset.map(|next_offset| {
+        format!(
+            "{}/v1/playlists/{}/tracks?offset={}&limit={}",
+            base, id, next_offset, limit
+        )
+    });
+    Json(json!({
+        "items": items,
+        "limit": limit,
+        "offset": offset,
+        "total": TOTAL,
+        "next": next,
+    }))
+    .into_response()
+}
+
+/// Queues whatever uri it's given, as long as it comes with the user's token.
+async fn queue(
+    State(state): State<Arc<Mutex<MockState>>>,
+    Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+) -> Response {
+    let bearer = format!("Bearer {}", USER_TOKEN);
+    if headers
+        .get(header::AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        != Some(&bearer)
+    {
+        return unauthorized();
+    }
+    match params.get("uri") {
+        Some(uri) => {
+            state.lock().queued.push(uri.clone());
+            StatusCode::NO_CONTENT.into_response()
+        }
+        None => StatusCode::BAD_REQUEST.into_response(),
+    }
+}
+
+/// A CAPTCHA provider that only likes GOOD_CAPTCHA.
+async fn siteverify(Form(params): Form<HashMap<String, String>>) -> Response {
+    let success = params.get("secret").map(String::as_str) == Some(CAPTCHA_SECRET)
+        && params.get("response").map(String::as_str) == Some(GOOD_CAPTCHA);
+    Json(json!({"success": success})).into_response()
+}
+
+fn unauthorized() -> Response {
+    (
+        StatusCode::UNAUTHORIZED,
+        Json(json!({"error": {"status": 401, "message": "The access token expired"}})),
+    )
+        .into_response()
+}
@@ -0,0 +1,288 @@
+// Each test binary only uses some of this.
+#![allow(dead_code)]
+
+pub mod mock_acme;
+pub mod mock_spotify;
+
+use mock_spotify::{MockSpotify, CLIENT_ID, CLIENT_SECRET};
+
+use obscurify::{Config, SpotifyClient};
+
+use reqwest::{header, redirect, StatusCode};
+
+use std::io::Read;
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+static RUNS: AtomicUsize = AtomicUsize::new(0);
+
+/// The real binary, running out of its own scratch directory against a MockSpotify.
+pub struct Obscurify {
+    pub base: String,
+    pub http: reqwest::Client,
+    child: Child,
+    dir: PathBuf,
+}
+
+impl Obscurify {
+    /// Starts the server with a [service] for the currently playing track, pointed at the mock.
+    /// `extra` gets tacked onto the end of the config, for any more sections a test needs.
+    pub async fn start(mock: &MockSpotify, extra: &str) -> Obscurify {
+        let spotify = format!(
+            "api_base: {}\naccounts_base: {}\n",
+            mock.api_base(),
+            mock.accounts_base()
+        );
+        Obscurify::start_with_spotify(&spotify, extra).await
+    }
+
+    /// Same as start, but with whatever [spotify] section the test likes.
+    pub async fn start_with_spotify(spotify: &str, extra: &str) -> Obscurify {
+        let server = Obscurify::spawn(free_port(), spotify, extra);
+        server.wait_until_up().await;
+        server
+    }
+
+    /// Starts the server with plain HTTP on `port`, without waiting for it to come up,
+    /// for when something else (a certificate, say) has to happen first.
+    pub fn spawn(port: u16, spotify: &str, extra: &str) -> Obscurify {
+        let dir = scratch_dir();
+        std::fs::create_dir_all(dir.join("api_keys")).unwrap();
+        std::fs::write(
+            dir.join("api_keys/spotify_client.txt"),
+            format!("{}:{}", CLIENT_ID, CLIENT_SECRET),
+        )
+        .unwrap();
+
+        std::fs::write(
+            dir.join("obsc.conf"),
+            format!(
+                "[routing]\n\
+                 http: 127.0.0.1:{port}\n\
+                 \n\
+                 [spotify]\n\
+                 {spotify}\n\
+                 [logging]\n\
+                 level: error\n\
+                 \n\
+                 [service]\n\
+                 uri: 127.0.0.1:{port}\n\
+                 redirect: http://127.0.0.1:{port}/authorized\n\
+                 domain: /current_track\n\
+                 target: api\n\
+                 endpoint: me/player/currently-playing\n\
+                 extract: item/id\n\
+                 \n\
+                 {extra}\n",
+                port = port,
+                spotify = spotify,
+                extra = extra,
+            ),
+        )
+        .unwrap();
+
+        let child = Command::new(env!("CARGO_BIN_EXE_obscurify"))
+            .arg("obsc.conf")
+            .current_dir(&dir)
+            .env_remove("RUST_LOG")
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        Obscurify {
+            base: format!("http://127.0.0.1:{}", port),
+            http: reqwest::Client::builder()
+                .redirect(redirect::Policy::none())
+                .build()
+                .unwrap(),
+            child,
+            dir,
+        }
+    }
+
+    async fn wait_until_up(&self) {
+        for _ in 0..100 {
+            if let Ok(response) = self.get("/healthz").await {
+                if response.status() == StatusCode::OK {
+                    return;
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        panic!("obscurify never came up on {}", self.base);
+    }
+
+    /// Runs one of the other subcommands (`logout`, say) against the same config.
+    pub fn run(&self, command: &str) -> std::process::Output {
+        self.command(command, "obsc.conf").output().unwrap()
+    }
+
+    /// A subcommand against some config in the server's directory, for when it needs driving.
+    pub fn command(&self, command: &str, config: &str) -> Command {
+        let mut cli = Command::new(env!("CARGO_BIN_EXE_obscurify"));
+        cli.args([command, config])
+            .current_dir(&self.dir)
+            .env_remove("RUST_LOG");
+        cli
+    }
+
+    /// Sends the server a signal, `HUP` say.
+    pub fn signal(&self, signal: &str) {
+        Command::new("kill")
+            .args([&format!("-{}", signal), &self.child.id().to_string()])
+            .status()
+            .unwrap();
+    }
+
+    /// Asks the server to stop the way systemd would, and waits for it to.
+    pub fn terminate(&mut self) {
+        self.signal("TERM");
+        let status = self.child.wait().unwrap();
+        assert!(status.success(), "obscurify exited with {}", status);
+    }
+
+    /// Waits (a few seconds at most) for a server that shouldn't have started to give up,
+    /// and hands back how it exited and what it said on the way out.
+    pub fn wait_for_exit(&mut self) -> (std::process::ExitStatus, String) {
+        for _ in 0..100 {
+            if let Some(status) = self.child.try_wait().unwrap() {
+                let mut stderr = String::new();
+                self.child
+                    .stderr
+                    .take()
+                    .unwrap()
+                    .read_to_string(&mut stderr)
+                    .unwrap();
+                return (status, stderr);
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        panic!("obscurify never exited");
+    }
+
+    /// Where it's running from, for looking at anything it writes.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub async fn get(&self, path: &str) -> reqwest::Result<reqwest::Response> {
+        self.http.get(format!("{}{}", self.base, path)).send().await
+    }
+
+    /// Clicks through /authenticate, the mock's consent "page" and back to /authorized.
+    pub async fn authorize(&self) {
+        let to_spotify = self.get("/authenticate").await.unwrap();
+        assert!(to_spotify.status().is_redirection());
+        let back = self.http.get(location(&to_spotify)).send().await.unwrap();
+        assert!(back.status().is_redirection());
+        let authorized = self.http.get(location(&back)).send().await.unwrap();
+        assert_eq!(authorized.status(), StatusCode::OK);
+        assert!(authorized
+            .text()
+            .await
+            .unwrap()
+            .starts_with("Successfully authorized!"));
+    }
+}
+
+impl Drop for Obscurify {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// A SpotifyClient pointed at the mock, for driving the library directly instead of the binary.
+/// Not started and not logged in.
+pub fn client(mock: &MockSpotify) -> Arc<SpotifyClient> {
+    client_at(&mock.api_base(), &mock.accounts_base())
+}
+
+/// Same as client, pointed wherever you like (somewhere nothing's listening, say).
+pub fn client_at(api_base: &str, accounts_base: &str) -> Arc<SpotifyClient> {
+    Arc::new(SpotifyClient::new(Arc::new(config_at(
+        api_base,
+        accounts_base,
+    ))))
+}
+
+/// The config client_at uses, credentials and all, for building things from it yourself.
+pub fn config_at(api_base: &str, accounts_base: &str) -> Config {
+    load_config(api_base, accounts_base, "").unwrap()
+}
+
+/// Same config with `extra` on the end, loaded or turned down.
+pub fn load_config(api_base: &str, accounts_base: &str, extra: &str) -> Result<Config, String> {
+    let dir = scratch_dir();
+    let path = dir.join("obsc.conf");
+    std::fs::write(
+        &path,
+        format!(
+            "[routing]\n\
+             http: 127.0.0.1:0\n\
+             \n\
+             [spotify]\n\
+             api_base: {}\n\
+             accounts_base: {}\n\
+             client_id: {}\n\
+             client_secret: {}\n\
+             \n\
+             [service]\n\
+             uri: 127.0.0.1\n\
+             redirect: http://127.0.0.1/spotify/authorized\n\
+             domain: /current_track\n\
+             target: api\n\
+             endpoint: me/player/currently-playing\n\
+             extract: item/id\n\
+             \n\
+             {}\n",
+            api_base, accounts_base, CLIENT_ID, CLIENT_SECRET, extra
+        ),
+    )
+    .unwrap();
+    let config = obscurify::conf::load(path.to_str().unwrap());
+    let _ = std::fs::remove_dir_all(&dir);
+    config
+}
+
+/// A fresh directory under the system temp dir. Whoever asked for it cleans it up.
+pub fn scratch_dir() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!(
+        "obscurify-test-{}-{}",
+        std::process::id(),
+        RUNS.fetch_add(1, Ordering::SeqCst)
+    ));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Where a redirect points.
+pub fn location(response: &reqwest::Response) -> String {
+    response
+        .headers()
+        .get(header::LOCATION)
+        .expect("Redirect without a Location!")
+        .to_str()
+        .unwrap()
+        .to_owned()
+}
+
+/// Where nothing's listening: a port that was free a moment ago.
+pub fn dead_base() -> String {
+    format!("http://127.0.0.1:{}/", free_port())
+}
+
+/// Grabs a port nobody's using right now. Something else could take it before the server does,
+/// but that's unlikely enough on a test box.
+pub fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
@@ -0,0 +1,107 @@
+//! Configs that don't make sense get turned down with a message, not a panic.
+
+mod common;
+
+/// Loads the usual test config plus `extra`, expecting it to fail, and hands back why.
+fn rejected(extra: &str) -> String {
+    let dead = common::dead_base();
+    match common::load_config(&dead, &dead, extra) {
+        Ok(_) => panic!("Loaded a bad config:\n{}", extra),
+        Err(e) => e,
+    }
+}
+
+#[test]
+fn wants_the_keys_every_service_needs() {
+    let e = rejected("[service.nowhere]\ndomain: /nowhere\nendpoint: me\nextract: id\n");
+    assert!(e.contains("target"), "{}", e);
+    let e = rejected("[service.nothing]\ndomain: /nothing\ntarget: api\nendpoint: me\n");
+    assert!(e.contains("extract"), "{}", e);
+    let e = rejected(
+        "[service.who]\ndomain: /who\ntarget: api\nendpoint: me\nextract: id\nauth: root\n",
+    );
+    assert!(e.contains("Unknown auth"), "{}", e);
+}
+
+#[test]
+fn turns_down_bad_write_services() {
+    let write = "[service.write]\ndomain: /write\ntarget: api\nmethod: post\n";
+    let e = rejected(&format!("{}endpoint: me/player/pause\n", write));
+    assert!(e.contains("can't write to"), "{}", e);
+    let e = rejected(&format!(
+        "{}endpoint: me/player/queue\ncaptcha_verify: https://example.com/siteverify\n",
+        write
+    ));
+    assert!(e.contains("captcha_secret"), "{}", e);
+    let e = rejected(&format!(
+        "{}endpoint: me/player/queue\nrate_limit: lots\n",
+        write
+    ));
+    assert!(e.contains("service write"), "{}", e);
+    let e = rejected(&format!("{}endpoint: me/player/queue\nauth: app\n", write));
+    assert!(e.contains("app token"), "{}", e);
+}
+
+#[test]
+fn wants_domain_and_endpoint_to_agree_on_path_params() {
+    let service = "[service.playlist]\ntarget: api\nextract: id\n";
+    let e = rejected(&format!(
+        "{}domain: /playlist\nendpoint: playlists/{{id}}\n",
+        service
+    ));
+    assert!(e.contains("no :id"), "{}", e);
+    let e = rejected(&format!(
+        "{}domain: /playlist/:id\nendpoint: playlists/{{id}}\n",
+        service
+    ));
+    assert!(e.contains("path.id"), "{}", e);
+    let e = rejected(&format!(
+        "{}domain: /playlist/:id\nendpoint: playlists/{{id}}\npath.id: re:[\n",
+        service
+    ));
+    assert!(e.contains("path.id in service playlist"), "{}", e);
+    let e = rejected(&format!(
+        "{}domain: /playlist/:id\nendpoint: me\npath.id: ours\n",
+        service
+    ));
+    assert!(e.contains("never uses it"), "{}", e);
+}
+
+#[test]
+fn turns_down_bad_query_params() {
+    let service = "[service.top]\ndomain: /top\ntarget: api\nextract: items\n";
+    let e = rejected(&format!(
+        "{}endpoint: me/top/tracks?limit=5\nquery.limit: 1..50\n",
+        service
+    ));
+    assert!(e.contains("already has limit"), "{}", e);
+    let e = rejected(&format!(
+        "{}endpoint: me/top/tracks\nquery.limit: re:(\n",
+        service
+    ));
+    assert!(e.contains("service top"), "{}", e);
+}
+
+#[test]
+fn turns_down_bad_cors_policies() {
+    let service = "[service.cors]\ndomain: /cors\ntarget: api\nendpoint: me\nextract: id\n";
+    let e = rejected(&format!("{}cors_origins: https://example.com/\n", service));
+    assert!(e.contains("Invalid CORS origin"), "{}", e);
+    let e = rejected(&format!(
+        "{}cors_origins: *\ncors_credentials: true\n",
+        service
+    ));
+    assert!(e.contains("every origin"), "{}", e);
+    let e = rejected(&format!("{}cors_max_age: forever\n", service));
+    assert!(e.contains("cors_max_age"), "{}", e);
+}
+
+#[test]
+fn turns_down_bad_pagination() {
+    let service =
+        "[service.all]\ndomain: /all\ntarget: api\nendpoint: me/playlists\nextract: items\n";
+    let e = rejected(&format!("{}paginate: yes\n", service));
+    assert!(e.contains("paginate"), "{}", e);
+    let e = rejected(&format!("{}paginate: true\nmax_items: 0\n", service));
+    assert!(e.contains("max_items"), "{}", e);
+}
@@ -0,0 +1,71 @@
+//! obscurify's routes nested in somebody else's axum app, with no binary in sight.
+
+mod common;
+
+use common::mock_spotify::{MockSpotify, TRACK_ID};
+
+use axum::{routing::get, Router};
+
+use reqwest::StatusCode;
+
+use tokio::net::TcpListener;
+
+#[tokio::test]
+async fn serves_from_a_nested_router() {
+    let mock = MockSpotify::start().await;
+    let spotify = common::client(&mock);
+    let app = Router::new()
+        .route("/", get(|| async { "the host app" }))
+        .nest("/spotify", obscurify::router_with_client(spotify.clone()));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let base = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, app).await });
+
+    let http = reqwest::Client::new();
+    let host = http.get(format!("{}/", base)).send().await.unwrap();
+    assert_eq!(host.text().await.unwrap(), "the host app");
+    let before = http
+        .get(format!("{}/spotify/current_track", base))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(before.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+    // Log in through the client directly rather than clicking through the redirects.
+    let tokens = spotify
+        .redeem_code(common::mock_spotify::CODE)
+        .await
+        .unwrap();
+    spotify.log_in(tokens);
+    let track = http
+        .get(format!("{}/spotify/current_track", base))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(track.status(), StatusCode::OK);
+    assert_eq!(track.text().await.unwrap(), TRACK_ID);
+}
+
+#[tokio::test]
+async fn builds_everything_from_the_config() {
+    let mock = MockSpotify::start().await;
+    // No api_keys file anywhere near; the credentials are all in the config.
+    let app = obscurify::router(common::config_at(&mock.api_base(), &mock.accounts_base()));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let base = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, app).await });
+
+    let to_spotify = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap()
+        .get(format!("{}/authenticate", base))
+        .send()
+        .await
+        .unwrap();
+    assert!(to_spotify.status().is_redirection());
+    let location = to_spotify.headers()[reqwest::header::LOCATION]
+        .to_str()
+        .unwrap();
+    assert!(location.contains(&format!("client_id={}", common::mock_spotify::CLIENT_ID)));
+}
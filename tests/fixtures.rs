@@ -0,0 +1,63 @@
+//! Recording upstream responses to fixtures and serving them back without Spotify.
+
+mod common;
+
+use common::mock_spotify::{MockSpotify, TRACK_ID, USER_TOKEN};
+use common::{scratch_dir, Obscurify};
+
+use reqwest::StatusCode;
+
+#[tokio::test]
+async fn replays_what_it_recorded() {
+    let fixtures = scratch_dir();
+    {
+        let mock = MockSpotify::start().await;
+        let spotify = format!(
+            "api_base: {}\naccounts_base: {}\nrecord: {}\n",
+            mock.api_base(),
+            mock.accounts_base(),
+            fixtures.display()
+        );
+        let server = Obscurify::start_with_spotify(&spotify, "").await;
+        server.authorize().await;
+        let track = server.get("/current_track").await.unwrap();
+        assert_eq!(track.text().await.unwrap(), TRACK_ID);
+    }
+
+    // The query string's part of the name, including the additional_types we tack on.
+    let recorded = std::fs::read_to_string(
+        fixtures.join("api/me_player_currently-playing_additional_types_track_episode.json"),
+    )
+    .unwrap();
+    assert!(recorded.contains(TRACK_ID));
+    assert!(!recorded.contains(USER_TOKEN));
+
+    // Nothing's listening on the discard port, so anything that goes upstream fails.
+    let spotify = format!(
+        "api_base: http://127.0.0.1:9/v1/\naccounts_base: http://127.0.0.1:9/\nreplay: {}\n",
+        fixtures.display()
+    );
+    let server = Obscurify::start_with_spotify(&spotify, "").await;
+    // No need to log in; replaying makes up its own tokens.
+    let track = server.get("/current_track").await.unwrap();
+    assert_eq!(track.status(), StatusCode::OK);
+    assert_eq!(track.text().await.unwrap(), TRACK_ID);
+
+    drop(server);
+    std::fs::remove_dir_all(&fixtures).unwrap();
+}
+
+#[tokio::test]
+async fn missing_fixtures_are_errors() {
+    let fixtures = scratch_dir();
+    let spotify = format!(
+        "api_base: http://127.0.0.1:9/v1/\naccounts_base: http://127.0.0.1:9/\nreplay: {}\n",
+        fixtures.display()
+    );
+    let server = Obscurify::start_with_spotify(&spotify, "").await;
+    let track = server.get("/current_track").await.unwrap();
+    assert_eq!(track.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+    drop(server);
+    std::fs::remove_dir_all(&fixtures).unwrap();
+}
@@ -0,0 +1,194 @@
+//! The whole /authenticate → /authorized → service route dance, against a mock Spotify.
+
+mod common;
+
+use common::mock_spotify::{MockSpotify, Playing, EPISODE_ID, TRACK_ID};
+use common::Obscurify;
+
+use reqwest::StatusCode;
+
+#[tokio::test]
+async fn serves_the_current_track_once_authorized() {
+    let mock = MockSpotify::start().await;
+    let server = Obscurify::start(&mock, "").await;
+
+    let before = server.get("/current_track").await.unwrap();
+    assert_eq!(before.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let ready = server.get("/readyz").await.unwrap();
+    assert_eq!(ready.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+    server.authorize().await;
+    assert_eq!(mock.grants(), vec!["authorization_code"]);
+
+    let track = server.get("/current_track").await.unwrap();
+    assert_eq!(track.status(), StatusCode::OK);
+    assert_eq!(track.text().await.unwrap(), TRACK_ID);
+    let ready = server.get("/readyz").await.unwrap();
+    assert_eq!(ready.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn passes_nothing_playing_through() {
+    let mock = MockSpotify::start().await;
+    let server = Obscurify::start(&mock, "").await;
+    server.authorize().await;
+
+    mock.set_playing(Playing::Nothing);
+    let nothing = server.get("/current_track").await.unwrap();
+    assert_eq!(nothing.status(), StatusCode::NO_CONTENT);
+}
+
+#[tokio::test]
+async fn says_what_kind_of_item_is_playing() {
+    let mock = MockSpotify::start().await;
+    let server = Obscurify::start(&mock, "").await;
+    server.authorize().await;
+
+    let track = server.get("/current_track").await.unwrap();
+    assert_eq!(track.headers()["x-item-type"], "track");
+    assert_eq!(track.text().await.unwrap(), TRACK_ID);
+
+    mock.set_playing(Playing::Episode);
+    let episode = server.get("/current_track").await.unwrap();
+    assert_eq!(episode.status(), StatusCode::OK);
+    assert_eq!(episode.headers()["x-item-type"], "episode");
+    assert_eq!(episode.text().await.unwrap(), EPISODE_ID);
+
+    // Ads don't have an item, so there's nothing to hand back.
+    mock.set_playing(Playing::Ad);
+    let ad = server.get("/current_track").await.unwrap();
+    assert_eq!(ad.status(), StatusCode::NO_CONTENT);
+    assert_eq!(ad.headers()["x-item-type"], "ad");
+}
+
+#[tokio::test]
+async fn turns_upstream_errors_into_500s() {
+    let mock = MockSpotify::start().await;
+    let server = Obscurify::start(&mock, "").await;
+    server.authorize().await;
+
+    mock.set_playing(Playing::RateLimited);
+    let limited = server.get("/current_track").await.unwrap();
+    assert_eq!(limited.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    let ready = server.get("/readyz").await.unwrap();
+    assert_eq!(ready.status(), StatusCode::OK);
+
+    mock.set_playing(Playing::Unauthorized);
+    let unauthorized = server.get("/current_track").await.unwrap();
+    assert_eq!(unauthorized.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    // A 401 means the token's no good any more, which readiness should own up to.
+    let ready = server.get("/readyz").await.unwrap();
+    assert_eq!(ready.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+    mock.set_playing(Playing::Garbage);
+    let garbage = server.get("/current_track").await.unwrap();
+    assert_eq!(garbage.status(), StatusCode::BAD_GATEWAY);
+}
+
+#[tokio::test]
+async fn survives_an_unreachable_api() {
+    let mock = MockSpotify::start().await;
+    let spotify = format!(
+        "api_base: {}\naccounts_base: {}\n",
+        common::dead_base(),
+        mock.accounts_base()
+    );
+    let server = Obscurify::start_with_spotify(&spotify, "").await;
+    server.authorize().await;
+
+    let unreachable = server.get("/current_track").await.unwrap();
+    assert_eq!(unreachable.status(), StatusCode::BAD_GATEWAY);
+    let healthy = server.get("/healthz").await.unwrap();
+    assert_eq!(healthy.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn turns_away_bad_redirects() {
+    let mock = MockSpotify::start().await;
+    let server = Obscurify::start(&mock, "").await;
+
+    for query in ["", "?code=x", "?state=wrong&code=x"] {
+        let redirect = server.get(&format!("/authorized{}", query)).await.unwrap();
+        assert_eq!(redirect.status(), StatusCode::BAD_REQUEST, "{}", query);
+    }
+
+    let to_spotify = server.get("/authenticate").await.unwrap();
+    let back = server
+        .http
+        .get(common::location(&to_spotify))
+        .send()
+        .await
+        .unwrap();
+    let redirect = common::location(&back);
+    let state = reqwest::Url::parse(&redirect)
+        .unwrap()
+        .query_pairs()
+        .find(|(name, _)| name == "state")
+        .unwrap()
+        .1
+        .into_owned();
+    // Nothing in the error message gives the state away.
+    let denied = server
+        .get(&format!("/authorized?state={}&error=access_denied", state))
+        .await
+        .unwrap();
+    assert_eq!(denied.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(denied.text().await.unwrap(), "access_denied");
+
+    let authorized = server.http.get(&redirect).send().await.unwrap();
+    assert_eq!(authorized.status(), StatusCode::OK);
+    // The state's spent once it's worked.
+    let replayed = server.http.get(&redirect).send().await.unwrap();
+    assert_eq!(replayed.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn refuses_a_second_authorization() {
+    let mock = MockSpotify::start().await;
+    let server = Obscurify::start(&mock, "").await;
+    server.authorize().await;
+
+    let again = server.get("/authenticate").await.unwrap();
+    assert_eq!(again.status(), StatusCode::OK);
+    assert!(again
+        .text()
+        .await
+        .unwrap()
+        .starts_with("Access token already present!"));
+}
+
+#[tokio::test]
+async fn stays_logged_out_after_a_cli_logout() {
+    let mock = MockSpotify::start().await;
+    let mut server = Obscurify::start(&mock, "[tokens]\nstore: tokens.json\n").await;
+    server.authorize().await;
+    let store = server.dir().join("tokens.json");
+    assert!(store.exists());
+
+    // Without an admin secret the running server doesn't hear about it,
+    // but it mustn't write the tokens back on its way down either.
+    assert!(server.run("logout").status.success());
+    assert!(!store.exists());
+    server.terminate();
+    assert!(!store.exists());
+}
+
+#[tokio::test]
+async fn keeps_metrics_for_the_admin() {
+    let mock = MockSpotify::start().await;
+    let open = Obscurify::start(&mock, "").await;
+    let no_admin = open.get("/metrics").await.unwrap();
+    assert_eq!(no_admin.status(), StatusCode::NOT_FOUND);
+
+    let server = Obscurify::start(&mock, "[admin]\nsecret: hunter2\n").await;
+    let anonymous = server.get("/metrics").await.unwrap();
+    assert_eq!(anonymous.status(), StatusCode::UNAUTHORIZED);
+    let admin = server
+        .http
+        .get(format!("{}/metrics", server.base))
+        .bearer_auth("hunter2")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(admin.status(), StatusCode::OK);
+}
@@ -0,0 +1,89 @@
+//! Serving with a certificate of our own, and swapping in a new one without a restart.
+
+mod common;
+
+use common::mock_spotify::MockSpotify;
+use common::Obscurify;
+
+use reqwest::StatusCode;
+
+use std::path::Path;
+use std::time::Duration;
+
+/// A fresh self-signed certificate for localhost, written over whatever's at `dir`'s cert.pem
+/// and key.pem. Hands back the certificate, for trusting it.
+fn new_certificate(dir: &Path) -> reqwest::Certificate {
+    let cert = rcgen::generate_simple_self_signed(vec![String::from("localhost")]).unwrap();
+    let pem = cert.serialize_pem().unwrap();
+    std::fs::write(dir.join("key.pem"), cert.serialize_private_key_pem()).unwrap();
+    std::fs::write(dir.join("cert.pem"), &pem).unwrap();
+    reqwest::Certificate::from_pem(pem.as_bytes()).unwrap()
+}
+
+/// Whether a client that trusts only `cert` gets through. A new client every time,
+/// so there's no pooled connection from before a reload.
+async fn trusted(cert: &reqwest::Certificate, https_port: u16) -> bool {
+    let http = reqwest::Client::builder()
+        .tls_built_in_root_certs(false)
+        .add_root_certificate(cert.clone())
+        .build()
+        .unwrap();
+    match http
+        .get(format!("https://localhost:{}/healthz", https_port))
+        .send()
+        .await
+    {
+        Ok(response) => response.status() == StatusCode::OK,
+        Err(_) => false,
+    }
+}
+
+/// Gives `check` five seconds to come true.
+async fn eventually<F, Fut>(check: F) -> bool
+where
+    F: Fn() -> Fut,
+    Fut: std::future::Future<Output = bool>,
+{
+    for _ in 0..50 {
+        if check().await {
+            return true;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    false
+}
+
+#[tokio::test]
+async fn reloads_the_certificate_on_sighup() {
+    let mock = MockSpotify::start().await;
+    let certs = common::scratch_dir();
+    let first = new_certificate(&certs);
+    let https_port = common::free_port();
+    let server = Obscurify::spawn(
+        common::free_port(),
+        &format!(
+            "api_base: {}\naccounts_base: {}\n",
+            mock.api_base(),
+            mock.accounts_base()
+        ),
+        &format!(
+            "[routing]\nhttps: 127.0.0.1:{}\n\n[https]\ncert: {}\nkey: {}\n",
+            https_port,
+            certs.join("cert.pem").display(),
+            certs.join("key.pem").display()
+        ),
+    );
+    assert!(
+        eventually(|| trusted(&first, https_port)).await,
+        "HTTPS never came up"
+    );
+
+    let second = new_certificate(&certs);
+    server.signal("HUP");
+    assert!(
+        eventually(|| trusted(&second, https_port)).await,
+        "Still serving the old certificate"
+    );
+    assert!(!trusted(&first, https_port).await);
+    let _ = std::fs::remove_dir_all(&certs);
+}
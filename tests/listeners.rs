@@ -0,0 +1,67 @@
+//! What the server will and won't start up with.
+
+mod common;
+
+use common::mock_spotify::MockSpotify;
+use common::Obscurify;
+
+fn spotify(mock: &MockSpotify) -> String {
+    format!(
+        "api_base: {}\naccounts_base: {}\n",
+        mock.api_base(),
+        mock.accounts_base()
+    )
+}
+
+#[tokio::test]
+async fn serves_over_a_unix_socket_left_behind() {
+    let mock = MockSpotify::start().await;
+    let dir = common::scratch_dir();
+    let path = dir.join("obscurify.sock");
+    // An earlier run's socket, still lying around.
+    drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+
+    let server = Obscurify::start_with_spotify(
+        &spotify(&mock),
+        &format!("[routing]\nunix: {}\n", path.display()),
+    )
+    .await;
+    assert!(std::os::unix::net::UnixStream::connect(&path).is_ok());
+    drop(server);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn wont_replace_a_file_with_a_socket() {
+    let mock = MockSpotify::start().await;
+    let dir = common::scratch_dir();
+    let path = dir.join("important.txt");
+    std::fs::write(&path, "don't lose me").unwrap();
+
+    let mut server = Obscurify::spawn(
+        common::free_port(),
+        &spotify(&mock),
+        &format!("[routing]\nunix: {}\n", path.display()),
+    );
+    let (status, stderr) = server.wait_for_exit();
+    assert_eq!(status.code(), Some(1));
+    assert!(stderr.contains("isn't a socket"), "{}", stderr);
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "don't lose me");
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn says_so_when_the_certificate_is_missing() {
+    let mock = MockSpotify::start().await;
+    let mut server = Obscurify::spawn(
+        common::free_port(),
+        &spotify(&mock),
+        &format!(
+            "[routing]\nhttps: 127.0.0.1:{}\n\n[https]\ncert: missing.pem\nkey: missing.key\n",
+            common::free_port()
+        ),
+    );
+    let (status, stderr) = server.wait_for_exit();
+    assert_eq!(status.code(), Some(1));
+    assert!(stderr.contains("Failed to load missing.pem"), "{}", stderr);
+}
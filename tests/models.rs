@@ -0,0 +1,109 @@
+//! The typed models against payloads shaped like Spotify's, gaps and nulls included.
+
+mod common;
+
+use common::mock_spotify::{MockSpotify, Playing, CODE, TRACK_ID};
+
+use obscurify::models::{CurrentlyPlaying, PlayableItem, PlayingType, Playlist};
+
+use serde_json::json;
+
+#[test]
+fn reads_a_playing_track() {
+    let playing: CurrentlyPlaying = serde_json::from_value(json!({
+        "timestamp": 1700000000000u64,
+        "progress_ms": 42000,
+        "is_playing": true,
+        "currently_playing_type": "track",
+        "context": {"type": "playlist", "uri": "spotify:playlist:abc", "href": "x"},
+        "actions": {"disallows": {"resuming": true}},
+        "item": {
+            "type": "track",
+            "id": TRACK_ID,
+            "name": "Mock Track",
+            "duration_ms": 215000,
+            "album": {"id": "al", "name": "Mock Album", "images": []},
+            "artists": [{"id": "ar", "name": "Mock Artist"}],
+        },
+    }))
+    .unwrap();
+    assert!(playing.is_playing);
+    assert_eq!(playing.progress_ms, Some(42000));
+    assert_eq!(playing.currently_playing_type, PlayingType::Track);
+    match playing.item.unwrap() {
+        PlayableItem::Track(track) => {
+            assert_eq!(track.id.as_deref(), Some(TRACK_ID));
+            assert_eq!(track.artists[0].name, "Mock Artist");
+        }
+        other => panic!("Expected a track, got {:?}", other),
+    }
+}
+
+#[test]
+fn reads_episodes_and_ads() {
+    let episode: CurrentlyPlaying = serde_json::from_value(json!({
+        "is_playing": true,
+        "currently_playing_type": "episode",
+        "item": {
+            "type": "episode",
+            "id": "ep",
+            "name": "Mock Episode",
+            "duration_ms": 3600000,
+            "show": {"id": "sh", "name": "Mock Show", "publisher": "Someone"},
+        },
+    }))
+    .unwrap();
+    assert_eq!(episode.currently_playing_type, PlayingType::Episode);
+    assert_eq!(episode.item.as_ref().and_then(|i| i.id()), Some("ep"));
+
+    let ad: CurrentlyPlaying = serde_json::from_value(json!({
+        "is_playing": true,
+        "currently_playing_type": "ad",
+        "item": null,
+    }))
+    .unwrap();
+    assert_eq!(ad.currently_playing_type, PlayingType::Ad);
+    assert!(ad.item.is_none());
+}
+
+#[test]
+fn reads_a_playlist_with_holes_in_it() {
+    let playlist: Playlist = serde_json::from_value(json!({
+        "id": "pl",
+        "name": "Mock Playlist",
+        "description": null,
+        "public": true,
+        "images": null,
+        "owner": {"id": "me", "display_name": null},
+        "tracks": {
+            "items": [
+                {"added_at": "2024-01-01T00:00:00Z", "track": null},
+                {"added_at": null, "track": {"type": "track", "id": null, "name": "Local", "duration_ms": 1, "is_local": true}},
+            ],
+            "limit": 100,
+            "offset": 0,
+            "total": 2,
+            "next": null,
+        },
+    }))
+    .unwrap();
+    assert_eq!(playlist.tracks.items.len(), 2);
+    assert!(playlist.tracks.items[0].track.is_none());
+    assert_eq!(playlist.tracks.items[1].track.as_ref().unwrap().id(), None);
+}
+
+#[tokio::test]
+async fn fetches_currently_playing_typed() {
+    let mock = MockSpotify::start().await;
+    let spotify = common::client(&mock);
+    spotify.log_in(spotify.redeem_code(CODE).await.unwrap());
+
+    let playing = spotify.currently_playing().await.unwrap().unwrap();
+    assert_eq!(playing.item.unwrap().id(), Some(TRACK_ID));
+
+    mock.set_playing(Playing::Nothing);
+    assert!(spotify.currently_playing().await.unwrap().is_none());
+
+    mock.set_playing(Playing::Unauthorized);
+    assert!(spotify.currently_playing().await.is_err());
+}
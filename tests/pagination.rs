@@ -0,0 +1,105 @@
+//! Services that gather every page of a list before picking out what they serve.
+
+mod common;
+
+use common::mock_spotify::MockSpotify;
+use common::Obscurify;
+
+use reqwest::{header, StatusCode};
+
+use serde_json::Value;
+
+fn tracks(extract: &str, extra: &str) -> String {
+    format!(
+        "[service.tracks]\n\
+         domain: /tracks/:id\n\
+         target: api\n\
+         endpoint: playlists/{{id}}/tracks\n\
+         extract: {}\n\
+         path.id: ours|elsewhere|circular|endless\n\
+         paginate: true\n\
+         {}\n",
+        extract, extra
+    )
+}
+
+#[tokio::test]
+async fn follows_next_links_to_the_end() {
+    let mock = MockSpotify::start().await;
+    let server = Obscurify::start(&mock, &tracks("items/6/track/id", "")).await;
+    server.authorize().await;
+
+    let last = server.get("/tracks/ours").await.unwrap();
+    assert_eq!(last.status(), StatusCode::OK);
+    assert_eq!(last.text().await.unwrap(), "track6");
+}
+
+#[tokio::test]
+async fn stops_at_max_items() {
+    let mock = MockSpotify::start().await;
+    let server = Obscurify::start(&mock, &tracks("items/4/track/id", "max_items: 5")).await;
+    server.authorize().await;
+
+    let fifth = server.get("/tracks/ours").await.unwrap();
+    assert_eq!(fifth.text().await.unwrap(), "track4");
+
+    let server = Obscurify::start(&mock, &tracks("items/5/track/id", "max_items: 5")).await;
+    server.authorize().await;
+    let sixth = server.get("/tracks/ours").await.unwrap();
+    assert_eq!(sixth.status(), StatusCode::NO_CONTENT);
+}
+
+#[tokio::test]
+async fn wont_follow_links_off_the_api() {
+    let mock = MockSpotify::start().await;
+    let server = Obscurify::start(&mock, &tracks("items/2/track/id", "")).await;
+    server.authorize().await;
+
+    // The first page is fine; the link to the second goes somewhere else, so that's all we get.
+    let third = server.get("/tracks/elsewhere").await.unwrap();
+    assert_eq!(third.text().await.unwrap(), "track2");
+
+    let server = Obscurify::start(&mock, &tracks("items/3/track/id", "")).await;
+    server.authorize().await;
+    let fourth = server.get("/tracks/elsewhere").await.unwrap();
+    assert_eq!(fourth.status(), StatusCode::NO_CONTENT);
+}
+
+#[tokio::test]
+async fn stops_when_the_links_go_nowhere() {
+    let mock = MockSpotify::start().await;
+    let server = Obscurify::start(&mock, &tracks("items/6/track/id", "")).await;
+    server.authorize().await;
+    for id in ["circular", "endless"] {
+        let last = server.get(&format!("/tracks/{}", id)).await.unwrap();
+        assert_eq!(last.text().await.unwrap(), "track6", "{}", id);
+    }
+
+    // Going round again would have handed out the second page twice.
+    let server = Obscurify::start(&mock, &tracks("items/7/track/id", "")).await;
+    server.authorize().await;
+    let again = server.get("/tracks/circular").await.unwrap();
+    assert_eq!(again.status(), StatusCode::NO_CONTENT);
+}
+
+#[tokio::test]
+async fn serves_a_whole_list_as_json() {
+    let mock = MockSpotify::start().await;
+    let server = Obscurify::start(&mock, &tracks("items", "")).await;
+    server.authorize().await;
+
+    let items = server.get("/tracks/ours").await.unwrap();
+    assert_eq!(items.status(), StatusCode::OK);
+    assert_eq!(items.headers()[header::CONTENT_TYPE], "application/json");
+    let items: Value = items.json().await.unwrap();
+    let ids: Vec<&str> = items
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|item| item["track"]["id"].as_str().unwrap())
+        .collect();
+    assert_eq!(
+        ids,
+        (0..7).map(|i| format!("track{}", i)).collect::<Vec<_>>()
+    );
+}
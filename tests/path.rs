@@ -0,0 +1,61 @@
+//! One service for a whole family of resources, with only the allowed ones reachable.
+
+mod common;
+
+use common::mock_spotify::MockSpotify;
+use common::Obscurify;
+
+use reqwest::StatusCode;
+
+const PLAYLISTS: &str = "[service.playlist]\n\
+                         domain: /playlist/:id\n\
+                         target: api\n\
+                         endpoint: playlists/{id}\n\
+                         extract: name\n\
+                         path.id: ours1|ours2\n\
+                         \n\
+                         [service.any_playlist]\n\
+                         domain: /any_playlist/:id\n\
+                         target: api\n\
+                         endpoint: playlists/{id}\n\
+                         extract: name\n\
+                         path.id: re:[A-Za-z0-9]{22}\n";
+
+#[tokio::test]
+async fn fills_the_endpoint_in_from_the_path() {
+    let mock = MockSpotify::start().await;
+    let server = Obscurify::start(&mock, PLAYLISTS).await;
+    server.authorize().await;
+
+    let ours = server.get("/playlist/ours2").await.unwrap();
+    assert_eq!(ours.status(), StatusCode::OK);
+    assert_eq!(ours.text().await.unwrap(), "Playlist ours2");
+
+    let matched = server
+        .get("/any_playlist/37i9dQZF1DXcBWIGoYBM5M")
+        .await
+        .unwrap();
+    assert_eq!(
+        matched.text().await.unwrap(),
+        "Playlist 37i9dQZF1DXcBWIGoYBM5M"
+    );
+}
+
+#[tokio::test]
+async fn anything_else_never_reaches_spotify() {
+    let mock = MockSpotify::start().await;
+    let server = Obscurify::start(&mock, PLAYLISTS).await;
+    server.authorize().await;
+
+    for path in [
+        "/playlist/theirs",
+        "/any_playlist/tooshort",
+        "/any_playlist/37i9dQZF1DXcBWIGoYBM5M%2F..%2F..%2Fme",
+        "/any_playlist/..",
+        "/playlist/ours1%3Fmarket%3DUS",
+    ] {
+        let response = server.get(path).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND, "{}", path);
+    }
+    assert_eq!(mock.playlists_served(), 0);
+}
@@ -0,0 +1,75 @@
+//! Services that let clients pick some of the query string, and nothing more.
+
+mod common;
+
+use common::mock_spotify::MockSpotify;
+use common::Obscurify;
+
+use reqwest::StatusCode;
+
+const TOP_TRACKS: &str = "[service.top]\n\
+                          domain: /top\n\
+                          target: api\n\
+                          endpoint: me/top/tracks\n\
+                          extract: items/0/id\n\
+                          query.time_range: short_term|medium_term|long_term = short_term\n\
+                          query.limit: 1..10\n";
+
+#[tokio::test]
+async fn forwards_declared_parameters_and_defaults() {
+    let mock = MockSpotify::start().await;
+    let server = Obscurify::start(&mock, TOP_TRACKS).await;
+    server.authorize().await;
+
+    let defaults = server.get("/top").await.unwrap();
+    assert_eq!(defaults.text().await.unwrap(), "time_range=short_term");
+
+    let chosen = server
+        .get("/top?limit=5&time_range=long_term")
+        .await
+        .unwrap();
+    assert_eq!(chosen.text().await.unwrap(), "limit=5&time_range=long_term");
+
+    // Anything undeclared just doesn't make it upstream.
+    let extra = server.get("/top?offset=3&market=US").await.unwrap();
+    assert_eq!(extra.text().await.unwrap(), "time_range=short_term");
+}
+
+#[tokio::test]
+async fn rejects_values_outside_the_rules() {
+    let mock = MockSpotify::start().await;
+    let server = Obscurify::start(&mock, TOP_TRACKS).await;
+    server.authorize().await;
+
+    for bad in [
+        "/top?limit=11",
+        "/top?limit=0",
+        "/top?limit=five",
+        "/top?time_range=forever",
+        "/top?time_range=short_term%26market%3DUS",
+    ] {
+        let response = server.get(bad).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", bad);
+    }
+}
+
+#[tokio::test]
+async fn keeps_the_whole_pattern() {
+    let mock = MockSpotify::start().await;
+    let server = Obscurify::start(
+        &mock,
+        "[service.top]\n\
+         domain: /top\n\
+         target: api\n\
+         endpoint: me/top/tracks\n\
+         extract: items/0/id\n\
+         query.limit: re:[0-9=]{1,2}\n",
+    )
+    .await;
+    server.authorize().await;
+
+    let chosen = server.get("/top?limit=42").await.unwrap();
+    assert_eq!(chosen.text().await.unwrap(), "limit=42");
+    let long = server.get("/top?limit=420").await.unwrap();
+    assert_eq!(long.status(), StatusCode::BAD_REQUEST);
+}
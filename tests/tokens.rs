@@ -0,0 +1,83 @@
+//! Keeping tokens fresh when Spotify (or the network) isn't cooperating.
+
+mod common;
+
+use common::mock_spotify::{MockSpotify, REFRESH_TOKEN};
+
+use obscurify::health;
+
+use reqwest::StatusCode;
+
+#[tokio::test]
+async fn a_burst_of_requests_fetches_one_app_token() {
+    let mock = MockSpotify::start().await;
+    let spotify = common::client(&mock);
+
+    let waiting: Vec<_> = (0..10)
+        .map(|_| {
+            let spotify = spotify.clone();
+            tokio::spawn(async move { spotify.ensure_app_token().await })
+        })
+        .collect();
+    for request in waiting {
+        assert!(request.await.unwrap());
+    }
+    let fetched = mock
+        .grants()
+        .iter()
+        .filter(|grant| *grant == "client_credentials")
+        .count();
+    assert_eq!(fetched, 1);
+}
+
+#[tokio::test]
+async fn an_unreachable_token_endpoint_is_a_failed_refresh() {
+    let dead = common::dead_base();
+    let spotify = common::client_at(&dead, &dead);
+
+    assert!(!spotify.refresh_app_token().await);
+    assert!(!spotify.ensure_app_token().await);
+    assert_eq!(spotify.app_tokens().last_refresh(), Some(false));
+}
+
+#[tokio::test]
+async fn a_user_refresh_survives_an_unreachable_token_endpoint() {
+    let dead = common::dead_base();
+    let spotify = common::client_at(&dead, &dead);
+    spotify.user_tokens().swap(
+        serde_json::from_value(serde_json::json!({
+            "access_token": "stale",
+            "refresh_token": "refresh",
+            "expires_in": 0,
+        }))
+        .unwrap(),
+    );
+
+    assert!(!spotify.refresh_tokens().await);
+    assert_eq!(spotify.user_tokens().last_refresh(), Some(false));
+    assert_eq!(
+        spotify.user_tokens().access_token().as_deref(),
+        Some("stale")
+    );
+}
+
+#[tokio::test]
+async fn a_successful_refresh_gets_past_a_401() {
+    let mock = MockSpotify::start().await;
+    let spotify = common::client(&mock);
+    spotify.user_tokens().swap(
+        serde_json::from_value(serde_json::json!({
+            "access_token": "revoked",
+            "refresh_token": REFRESH_TOKEN,
+            "expires_in": 3600,
+        }))
+        .unwrap(),
+    );
+    spotify.user_tokens().record_upstream(401);
+    let ready = health::readyz(&[("user", spotify.user_tokens())]);
+    assert_eq!(ready.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+    assert!(spotify.refresh_tokens().await);
+    let ready = health::readyz(&[("user", spotify.user_tokens())]);
+    assert_eq!(ready.status(), StatusCode::OK);
+}